@@ -0,0 +1,146 @@
+// Latency of an unrelated endpoint while a storm of logins hashes passwords
+//
+// Runs the same router twice on a runtime with two workers: once verifying passwords inline in
+// the handler, as the handlers used to, and once through `password::Hasher`. Inline hashing
+// blocks the workers and the `/ping` latency climbs to the duration of several hashes, with the
+// hasher it stays in the sub-millisecond range.
+//
+//   cargo bench --bench login_storm
+
+use axum::{
+    Router,
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    routing::{get, post},
+};
+use rust_backend::password::{Hasher, Verification};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tower::ServiceExt;
+
+const WORKERS: usize = 2;
+const CONCURRENT_LOGINS: usize = 16;
+const PINGS: usize = 50;
+const PING_INTERVAL: Duration = Duration::from_millis(5);
+
+struct BenchState {
+    hasher: Hasher,
+    password_hash: String,
+    inline: bool,
+}
+
+async fn login(State(state): State<Arc<BenchState>>) -> StatusCode {
+    let valid = if state.inline {
+        use argon2::{Argon2, PasswordHash, PasswordVerifier};
+        let parsed_hash = PasswordHash::new(&state.password_hash).unwrap();
+        Argon2::default()
+            .verify_password(b"wrong password", &parsed_hash)
+            .is_ok()
+    } else {
+        state
+            .hasher
+            .verify("wrong password".to_owned(), state.password_hash.clone())
+            .await
+            .unwrap()
+            == Verification::Match { outdated: false }
+    };
+
+    match valid {
+        true => StatusCode::OK,
+        false => StatusCode::UNAUTHORIZED,
+    }
+}
+
+async fn ping() -> &'static str {
+    "pong"
+}
+
+async fn run(inline: bool, password_hash: String) -> Vec<Duration> {
+    let state = Arc::new(BenchState {
+        // Default Argon2 parameters, hashes limited to the number of workers
+        hasher: Hasher::new(19_456, 2, 1, WORKERS).unwrap(),
+        password_hash,
+        inline,
+    });
+    let app = Router::new()
+        .route("/login", post(login))
+        .route("/ping", get(ping))
+        .with_state(state);
+
+    let storm: Vec<_> = (0..CONCURRENT_LOGINS)
+        .map(|_| {
+            let app = app.clone();
+            tokio::spawn(async move {
+                loop {
+                    let request = Request::post("/login").body(Body::empty()).unwrap();
+                    let _ = app.clone().oneshot(request).await;
+                    // The next request of a real client arrives over the network, an inline
+                    // login never awaits anything and would otherwise keep the worker forever
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+
+    // Let the storm build up before measuring
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut latencies = Vec::with_capacity(PINGS);
+    for _ in 0..PINGS {
+        let started = Instant::now();
+        let app = app.clone();
+        // Spawned like a connection task, so it competes with the storm for the workers
+        tokio::spawn(async move {
+            let request = Request::get("/ping").body(Body::empty()).unwrap();
+            app.oneshot(request).await.unwrap()
+        })
+        .await
+        .unwrap();
+        latencies.push(started.elapsed());
+        tokio::time::sleep(PING_INTERVAL).await;
+    }
+
+    for task in storm {
+        task.abort();
+    }
+    latencies
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:<10} p50 {:>10.2?}  p90 {:>10.2?}  p99 {:>10.2?}  max {:>10.2?}",
+        name,
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(WORKERS)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let hasher = Hasher::new(19_456, 2, 1, 1).unwrap();
+        let password_hash = hasher.hash("correct password".to_owned()).await.unwrap();
+
+        println!(
+            "/ping latency with {} concurrent logins on {} workers",
+            CONCURRENT_LOGINS, WORKERS
+        );
+        report("inline", run(true, password_hash.clone()).await);
+        report("offloaded", run(false, password_hash).await);
+    });
+
+    // The aborted storm tasks may still be inside a hash, do not wait for them
+    runtime.shutdown_background();
+}
@@ -0,0 +1,10 @@
+create table "password_reset_tokens"
+(
+    token_hash text primary key,
+    user_id    uuid        not null references "users" (user_id) on delete cascade,
+    expires_at timestamptz not null,
+    used_at    timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index on "password_reset_tokens" (user_id);
@@ -0,0 +1,12 @@
+create table "sessions"
+(
+    session_id uuid primary key,
+    user_id    uuid        not null references "users" (user_id) on delete cascade,
+    created_at timestamptz not null default now(),
+    last_seen  timestamptz not null default now(),
+    user_agent text,
+    ip         text,
+    revoked_at timestamptz
+);
+
+create index on "sessions" (user_id) where revoked_at is null;
@@ -0,0 +1,12 @@
+-- All refresh tokens of a session form one token family. Presenting a token that was
+-- already rotated revokes the session and with it every token of the family.
+create table "refresh_tokens"
+(
+    token_hash text primary key,
+    session_id uuid        not null references "sessions" (session_id) on delete cascade,
+    expires_at timestamptz not null,
+    rotated_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index on "refresh_tokens" (session_id);
@@ -0,0 +1,19 @@
+create table "totp_secrets"
+(
+    user_id        uuid primary key references "users" (user_id) on delete cascade,
+    secret         text        not null,
+    -- Null until the user proved their authenticator works, unconfirmed secrets are not enforced
+    confirmed_at   timestamptz,
+    -- Time step of the last accepted code, codes of this or earlier steps are rejected
+    last_used_step bigint,
+    created_at     timestamptz not null default now()
+);
+
+create table "recovery_codes"
+(
+    user_id    uuid        not null references "users" (user_id) on delete cascade,
+    code_hash  text        not null,
+    used_at    timestamptz,
+    created_at timestamptz not null default now(),
+    primary key (user_id, code_hash)
+);
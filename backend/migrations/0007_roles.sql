@@ -0,0 +1,43 @@
+create table "roles"
+(
+    name        text primary key,
+    description text not null
+);
+
+create table "permissions"
+(
+    name        text primary key,
+    description text not null
+);
+
+create table "role_permissions"
+(
+    role       text not null references "roles" (name) on delete cascade,
+    permission text not null references "permissions" (name) on delete cascade,
+    primary key (role, permission)
+);
+
+create table "user_roles"
+(
+    user_id    uuid        not null references "users" (user_id) on delete cascade,
+    role       text        not null references "roles" (name) on delete cascade,
+    granted_at timestamptz not null default now(),
+    primary key (user_id, role)
+);
+
+insert into "roles" (name, description)
+values ('admin', 'Full access to all users and settings'),
+       ('user', 'Regular account');
+
+insert into "permissions" (name, description)
+values ('users:read', 'View other users'),
+       ('users:write', 'Modify, suspend and delete other users'),
+       ('roles:grant', 'Grant and revoke roles');
+
+insert into "role_permissions" (role, permission)
+select 'admin', name
+from "permissions";
+
+insert into "user_roles" (user_id, role)
+select user_id, 'user'
+from "users";
@@ -0,0 +1,3 @@
+alter table "users"
+    add column suspended_at      timestamptz,
+    add column suspension_reason text;
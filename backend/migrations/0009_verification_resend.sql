@@ -0,0 +1,6 @@
+alter table "users"
+    add column verification_sent_at timestamptz;
+
+update "users"
+set verification_sent_at = created_at
+where is_verified = false;
@@ -0,0 +1,32 @@
+-- Single-use tokens sent by email. The token handed out is `<token_id>.<secret>`, only a hash
+-- of the secret is stored and the id is used to look the row up.
+create table "email_tokens"
+(
+    token_id    uuid primary key,
+    user_id     uuid        not null references "users" (user_id) on delete cascade,
+    purpose     text        not null check (purpose in ('verify_email', 'change_email', 'reset_password')),
+    token_hash  text        not null,
+    expires_at  timestamptz not null,
+    consumed_at timestamptz,
+    created_at  timestamptz not null default now()
+);
+
+create index on "email_tokens" (user_id, purpose);
+create index on "email_tokens" (expires_at);
+
+-- Outstanding reset tokens cannot be converted, users have to request a new link
+drop table "password_reset_tokens";
+
+-- Links with plaintext verification tokens stop working. Unusable placeholders keep the
+-- affected accounts from being swept for a day, so their owners can request a new link.
+insert into "email_tokens" (token_id, user_id, purpose, token_hash, expires_at)
+select gen_random_uuid(), user_id, 'verify_email', '', now() + interval '1 day'
+from "users"
+where is_verified = false;
+
+update "users"
+set verification_sent_at = null
+where is_verified = false;
+
+alter table "users"
+    drop column verification_token;
@@ -0,0 +1,9 @@
+-- Pending email changes keep the requested address on the token sent to it, the address in
+-- "users" is only replaced once the link is opened
+alter table "email_tokens"
+    add column new_email text collate "case_insensitive";
+
+alter table "email_tokens"
+    drop constraint email_tokens_purpose_check,
+    add constraint email_tokens_purpose_check
+        check (purpose in ('verify_email', 'change_email', 'cancel_email_change', 'reset_password'));
@@ -0,0 +1,11 @@
+-- Failed logins per account and per client address. Accounts are keyed by the lowercased
+-- username, so unknown usernames are throttled exactly like existing ones.
+create table "login_throttles"
+(
+    scope        text        not null check (scope in ('account', 'ip')),
+    subject      text        not null,
+    failures     integer     not null default 0,
+    last_failure timestamptz not null default now(),
+    locked_until timestamptz,
+    primary key (scope, subject)
+);
@@ -0,0 +1,7 @@
+-- Token buckets of the Postgres rate limiting backend, shared by all instances
+create table "rate_limit_buckets"
+(
+    key        text primary key,
+    tokens     double precision not null,
+    updated_at timestamptz      not null default now()
+);
@@ -0,0 +1,20 @@
+-- Emails are queued here in the transaction of the change that triggers them and delivered by a
+-- background worker, so a failing mail server delays an email instead of losing it. Delivered
+-- messages are deleted, messages that failed too often stay as 'dead' until requeued.
+create table "email_outbox"
+(
+    message_id      uuid primary key,
+    recipient       text        not null,
+    subject         text        not null,
+    html            text        not null,
+    status          text        not null default 'pending' check (status in ('pending', 'dead')),
+    attempts        integer     not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    last_error      text,
+    created_at      timestamptz not null default now(),
+    updated_at      timestamptz
+);
+
+create index email_outbox_due_idx on email_outbox (next_attempt_at) where status = 'pending';
+
+SELECT trigger_updated_at('"email_outbox"');
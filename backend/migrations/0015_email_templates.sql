@@ -0,0 +1,8 @@
+-- Emails are sent in the language of the user, a BCP 47 tag like 'de' or 'pt-BR'. Without one
+-- the Accept-Language of the request is used, then the default locale.
+alter table "users"
+    add column locale text;
+
+-- Plain text alternative of the HTML body, empty for messages queued before it existed
+alter table "email_outbox"
+    add column text text not null default '';
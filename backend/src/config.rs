@@ -1,3 +1,11 @@
+use crate::http::rate_limit::{Backend, Quota};
+use crate::mailer::{SmtpSecurity, Transport};
+use crate::password_policy::CharClass;
+use anyhow::{Context, bail};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use url::Url;
+
 #[derive(clap::Parser)]
 pub struct Config {
     #[clap(long, env)]
@@ -6,21 +14,320 @@ pub struct Config {
     #[clap(long, env)]
     pub hmac_key: String,
 
+    /// Address to listen on, ignored with `unix_socket` or systemd socket activation
+    #[clap(long, env, default_value = "0.0.0.0")]
+    pub bind_address: IpAddr,
+
+    #[clap(long, env, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Listen on a Unix domain socket at this path instead, e.g. behind a reverse proxy
+    #[clap(long, env)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Addresses of reverse proxies whose `Forwarded` and `X-Forwarded-For` headers name the
+    /// client, comma separated. Peers on a Unix socket are always trusted.
+    #[clap(long, env, value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// PEM certificate chain, serves HTTPS together with `tls_key`
+    #[clap(long, env, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `tls_cert`
+    #[clap(long, env, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Interval in seconds to check the certificate and key for changes, rotated files are
+    /// loaded without a restart
+    #[clap(long, env, default_value_t = 60)]
+    pub tls_reload_interval: u64,
+
+    /// Seconds in-flight requests and background tasks get to finish after SIGTERM or SIGINT
+    #[clap(long, env, default_value_t = 30)]
+    pub shutdown_timeout: u64,
+
+    /// Seconds the server keeps accepting connections after SIGTERM or SIGINT while the
+    /// readiness probe already fails, so load balancers notice before connections are refused
+    #[clap(long, env, default_value_t = 0)]
+    pub shutdown_delay: u64,
+
+    /// Include the SMTP server in the readiness probe. Off by default, emails wait in the
+    /// outbox while it is down.
+    #[clap(long, env, default_value_t = false)]
+    pub health_check_smtp: bool,
+
+    /// Lifetime of the JWT access token in seconds
+    #[clap(long, env, default_value_t = 900)]
+    pub access_token_lifetime: i64,
+
+    /// Lifetime of a refresh token family in seconds, counted from login
+    #[clap(long, env, default_value_t = 2_592_000)]
+    pub refresh_token_lifetime: i64,
+
+    /// Failed logins after which an account is locked
+    #[clap(long, env, default_value_t = 10)]
+    pub login_lockout_threshold: i32,
+
+    /// Failed logins from one client address after which the address is locked, higher than
+    /// the account threshold because addresses are shared behind NAT
+    #[clap(long, env, default_value_t = 100)]
+    pub login_ip_lockout_threshold: i32,
+
+    /// Duration of a lockout in seconds, failures older than this are forgotten
+    #[clap(long, env, default_value_t = 900)]
+    pub login_lockout_duration: i64,
+
+    /// Delay in seconds after the first failed login, doubled with every further failure
+    #[clap(long, env, default_value_t = 1)]
+    pub login_backoff_base: i64,
+
+    /// Upper bound of the delay between failed logins in seconds
+    #[clap(long, env, default_value_t = 60)]
+    pub login_backoff_max: i64,
+
+    /// Argon2 memory cost of new password hashes in KiB
+    #[clap(long, env, default_value_t = 19_456)]
+    pub argon2_memory_kib: u32,
+
+    /// Argon2 iterations of new password hashes
+    #[clap(long, env, default_value_t = 2)]
+    pub argon2_iterations: u32,
+
+    /// Argon2 lanes of new password hashes
+    #[clap(long, env, default_value_t = 1)]
+    pub argon2_parallelism: u32,
+
+    /// Password hashes computed at the same time, defaults to the number of CPUs
+    #[clap(long, env)]
+    pub password_hash_concurrency: Option<usize>,
+
+    /// Minimum number of characters in a new password
+    #[clap(long, env, default_value_t = 8)]
+    pub password_min_length: usize,
+
+    /// Maximum number of characters in a new password
+    #[clap(long, env, default_value_t = 128)]
+    pub password_max_length: usize,
+
+    /// Character classes every new password needs, e.g. lowercase,uppercase,digit,symbol
+    #[clap(long, env, value_enum, value_delimiter = ',')]
+    pub password_required_classes: Vec<CharClass>,
+
+    /// Replaces the built-in list of common passwords, one password per line
+    #[clap(long, env)]
+    pub common_passwords_file: Option<PathBuf>,
+
+    /// Reject new passwords found in the Pwned Passwords breach corpus
+    #[clap(long, env, default_value_t = false)]
+    pub password_breach_check: bool,
+
+    /// Range endpoint of the Pwned Passwords API, the hash prefix is appended
+    #[clap(long, env, default_value = "https://api.pwnedpasswords.com/range/")]
+    pub pwned_passwords_url: String,
+
+    /// Where rate limit buckets are kept, use postgres when running several instances
+    #[clap(long, env, value_enum, default_value = "memory")]
+    pub rate_limit_backend: Backend,
+
+    /// Requests per client on every route, as <requests>/<seconds> or e.g. 10/min
+    #[clap(long, env, default_value = "600/60")]
+    pub rate_limit_default: Quota,
+
+    /// Login attempts per address, as <requests>/<seconds> or e.g. 10/min
+    #[clap(long, env, default_value = "20/60")]
+    pub rate_limit_login: Quota,
+
+    /// Requests per client to routes that send an email, as <requests>/<seconds> or e.g. 10/min
+    #[clap(long, env, default_value = "5/3600")]
+    pub rate_limit_email: Quota,
+
+    /// Answer every registration with 202 and email the owner of a taken address instead of
+    /// reporting duplicates, so the API cannot be used to find registered users
+    #[clap(long, env, default_value_t = false)]
+    pub registration_enumeration_safe: bool,
+
+    /// Base URL under which clients reach this API, links in emails start with it
+    #[clap(long, env, default_value = "http://localhost:8080")]
+    pub public_base_url: Url,
+
+    /// Base URL of the frontend, users are redirected there after following email links
+    #[clap(long, env, default_value = "http://localhost:3000")]
+    pub frontend_url: Url,
+
+    /// Origins allowed to call the API from a browser, comma separated. Defaults to the origin
+    /// of `frontend_url`.
+    #[clap(long, env, value_delimiter = ',')]
+    pub cors_allowed_origins: Vec<String>,
+
+    /// Username that is granted the admin role at startup, used to bootstrap the first admin
+    #[clap(long, env)]
+    pub initial_admin: Option<String>,
+
+    /// Issuer shown next to the account in authenticator apps
+    #[clap(long, env, default_value = "Minimal Axum Template")]
+    pub totp_issuer: String,
+
     #[clap(long, env)]
     pub mail_sender: String,
 
     #[clap(long, env)]
     pub mail_from: String,
 
-    #[clap(long, env)]
+    /// Where emails go, `file` and `stdout` are for development
+    #[clap(long, env, value_enum, default_value_t = Transport::Smtp)]
+    pub mail_transport: Transport,
+
+    #[clap(long, env, default_value = "localhost")]
     pub mail_host: String,
 
+    /// Defaults to the usual port of `mail_security`
+    #[clap(long, env)]
+    pub mail_port: Option<u16>,
+
+    /// `plain` is unencrypted, e.g. for MailHog on localhost:1025
+    #[clap(long, env, value_enum, default_value_t = SmtpSecurity::Tls)]
+    pub mail_security: SmtpSecurity,
+
+    /// Without a username and password the SMTP server is used without authentication
     #[clap(long, env)]
-    pub mail_port: u16,
+    pub mail_username: Option<String>,
 
     #[clap(long, env)]
-    pub mail_username: String,
+    pub mail_password: Option<String>,
 
+    /// Directory with email templates overriding the compiled-in ones, `<locale>/<name>.html`
+    /// and `<locale>/<name>.txt`. Every subdirectory adds a locale.
     #[clap(long, env)]
-    pub mail_password: String,
+    pub email_templates_dir: Option<PathBuf>,
+
+    /// Seconds after which a pooled SMTP connection is replaced
+    #[clap(long, env, default_value_t = 300)]
+    pub mail_connection_max_age: u64,
+
+    /// Seconds a pooled SMTP connection may sit unused before it is closed
+    #[clap(long, env, default_value_t = 60)]
+    pub mail_connection_idle_timeout: u64,
+
+    /// Directory the `file` transport writes .eml files to
+    #[clap(long, env, default_value = "mail")]
+    pub mail_dir: PathBuf,
+
+    /// Delivery attempts of an email before it is dead-lettered
+    #[clap(long, env, default_value_t = 8)]
+    pub email_max_attempts: i32,
+
+    /// Seconds before the first retry of a failed email, doubled for every further attempt
+    #[clap(long, env, default_value_t = 30)]
+    pub email_retry_base: u64,
+
+    /// Upper bound of the seconds between retries of a failed email
+    #[clap(long, env, default_value_t = 3600)]
+    pub email_retry_max: u64,
+
+    /// Seconds between checks for due emails when no new email wakes the worker up
+    #[clap(long, env, default_value_t = 10)]
+    pub email_outbox_poll_interval: u64,
+}
+
+impl Config {
+    /// Check what clap cannot, so a misconfigured instance fails at startup instead of sending
+    /// unusable links
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.unix_socket.is_some() && self.tls_cert.is_some() {
+            bail!("TLS is not supported on a Unix socket, the proxy in front terminates it");
+        }
+        if self.tls_reload_interval == 0 {
+            bail!("tls_reload_interval must be at least one second");
+        }
+        check_base_url(&self.public_base_url).context("invalid public_base_url")?;
+        check_base_url(&self.frontend_url).context("invalid frontend_url")?;
+
+        for origin in self.cors_origins() {
+            check_origin(&origin).with_context(|| format!("invalid CORS origin {:?}", origin))?;
+        }
+
+        let frontend_origin = self.frontend_url.origin().ascii_serialization();
+        if frontend_origin != self.public_base_url.origin().ascii_serialization()
+            && !self.cors_origins().contains(&frontend_origin)
+        {
+            log::warn!(
+                "The frontend at {} is not an allowed CORS origin, browsers will block its requests",
+                frontend_origin
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The allowed CORS origins, without trailing slashes
+    pub fn cors_origins(&self) -> Vec<String> {
+        match self.cors_allowed_origins.is_empty() {
+            true => vec![self.frontend_url.origin().ascii_serialization()],
+            false => self
+                .cors_allowed_origins
+                .iter()
+                .map(|origin| origin.trim().trim_end_matches('/').to_owned())
+                .collect(),
+        }
+    }
+
+    pub fn access_token_lifetime(&self) -> time::Duration {
+        time::Duration::seconds(self.access_token_lifetime)
+    }
+
+    pub fn refresh_token_lifetime(&self) -> time::Duration {
+        time::Duration::seconds(self.refresh_token_lifetime)
+    }
+
+    pub fn login_lockout_duration(&self) -> time::Duration {
+        time::Duration::seconds(self.login_lockout_duration)
+    }
+
+    pub fn mail_port(&self) -> u16 {
+        self.mail_port
+            .unwrap_or_else(|| self.mail_security.default_port())
+    }
+}
+
+// Every cookie is `Secure`, browsers only store those from https and from localhost
+fn check_base_url(url: &Url) -> anyhow::Result<()> {
+    match url.scheme() {
+        "https" => (),
+        "http" if is_loopback(url) => (),
+        "http" => bail!(
+            "{} must use https, browsers drop the Secure cookies of plain http sites other than localhost",
+            url
+        ),
+        scheme => bail!("{} has unsupported scheme {}", url, scheme),
+    }
+    if url.cannot_be_a_base() || url.host().is_none() {
+        bail!("{} is not a base URL", url);
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        bail!("{} must not have a query or fragment", url);
+    }
+
+    Ok(())
+}
+
+// An origin is scheme, host and port only, e.g. `https://app.example.com`
+fn check_origin(origin: &str) -> anyhow::Result<()> {
+    if origin == "*" {
+        bail!("a wildcard cannot be combined with credentialed requests");
+    }
+    let url = Url::parse(origin)?;
+    if url.origin().ascii_serialization() != origin {
+        bail!("expected only scheme, host and port");
+    }
+    check_base_url(&url)
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost" || domain.ends_with(".localhost"),
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
 }
@@ -0,0 +1,191 @@
+use crate::{
+    http::error::Error as HTTPError,
+    schemas::admin::{AdminUser, UserFilter},
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub async fn list_users(
+    filter: &UserFilter,
+    limit: i64,
+    offset: i64,
+    db: &PgPool,
+) -> Result<(Vec<AdminUser>, i64), HTTPError> {
+    /// List users matching a filter, newest first
+    ///
+    /// # Arguments
+    ///  filter: &UserFilter - Filters that are not set match every user
+    ///  limit: i64 - The maximum number of users to return
+    ///  offset: i64 - The number of matching users to skip
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(Vec<AdminUser>, i64), HTTPError> - The users on this page and the number of all
+    ///  matching users
+    // `LIKE` is not supported on the case insensitive collation, prefixes are compared in "C"
+    let rows = sqlx::query!(
+        r#"SELECT user_id, username, email, is_verified, created_at, suspended_at, suspension_reason,
+                  count(*) OVER () AS "total!"
+           FROM users
+           WHERE ($1::bool IS NULL OR is_verified = $1)
+             AND ($2::timestamptz IS NULL OR created_at >= $2)
+             AND ($3::timestamptz IS NULL OR created_at < $3)
+             AND ($4::text IS NULL
+                  OR starts_with(lower(username) COLLATE "C", lower($4))
+                  OR starts_with(lower(email) COLLATE "C", lower($4)))
+           ORDER BY created_at DESC, user_id
+           LIMIT $5 OFFSET $6"#,
+        filter.verified,
+        filter.created_after,
+        filter.created_before,
+        filter.prefix,
+        limit,
+        offset
+    )
+    .fetch_all(db)
+    .await?;
+
+    let total = rows.first().map(|row| row.total).unwrap_or(0);
+    let users = rows
+        .into_iter()
+        .map(|row| AdminUser {
+            id: row.user_id,
+            username: row.username,
+            email: row.email,
+            verified: row.is_verified,
+            created_at: row.created_at,
+            suspended_at: row.suspended_at,
+            suspension_reason: row.suspension_reason,
+        })
+        .collect();
+
+    Ok((users, total))
+}
+
+pub async fn get_user(user_id: &Uuid, db: &PgPool) -> Result<AdminUser, HTTPError> {
+    /// Get a user including the fields only admins may see
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<AdminUser, HTTPError> - The user, NotFound if there is no such user
+    let row = sqlx::query!(
+        "SELECT user_id, username, email, is_verified, created_at, suspended_at, suspension_reason
+         FROM users WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or(HTTPError::NotFound)?;
+
+    Ok(AdminUser {
+        id: row.user_id,
+        username: row.username,
+        email: row.email,
+        verified: row.is_verified,
+        created_at: row.created_at,
+        suspended_at: row.suspended_at,
+        suspension_reason: row.suspension_reason,
+    })
+}
+
+pub async fn count_active_sessions(user_id: &Uuid, db: &PgPool) -> Result<i64, HTTPError> {
+    /// Count the sessions of a user that have not been revoked
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<i64, HTTPError> - The number of active sessions
+    let row = sqlx::query!(
+        r#"SELECT count(*) AS "count!" FROM sessions WHERE user_id = $1 AND revoked_at IS NULL"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.count)
+}
+
+pub async fn suspend_user(user_id: &Uuid, reason: &str, db: &PgPool) -> Result<(), HTTPError> {
+    /// Suspend a user and revoke all of their sessions
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  reason: &str - Why the user was suspended, shown to admins
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if there is no such user
+    let mut tx = db.begin().await?;
+
+    let result = sqlx::query!(
+        "UPDATE users SET suspended_at = now(), suspension_reason = $2 WHERE user_id = $1",
+        user_id,
+        reason
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(HTTPError::NotFound);
+    }
+
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn unsuspend_user(user_id: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
+    /// Lift the suspension of a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if there is no such user
+    let result = sqlx::query!(
+        "UPDATE users SET suspended_at = NULL, suspension_reason = NULL WHERE user_id = $1",
+        user_id
+    )
+    .execute(db)
+    .await?;
+
+    match result.rows_affected() {
+        0 => Err(HTTPError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+pub async fn verify_user(user_id: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
+    /// Mark the email address of a user as verified without a verification token
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if there is no such user
+    let result = sqlx::query!(
+        "UPDATE users SET is_verified = true WHERE user_id = $1",
+        user_id
+    )
+    .execute(db)
+    .await?;
+
+    match result.rows_affected() {
+        0 => Err(HTTPError::NotFound),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,280 @@
+use crate::http::error::Error as HTTPError;
+use crate::schemas::admin::{OutboxMessage, OutboxStatus};
+use sqlx::{PgConnection, PgPool};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// Channel the worker listens on, notified when a message becomes due
+pub const CHANNEL: &str = "email_outbox";
+
+/// A message claimed for delivery
+pub struct Delivery {
+    pub message_id: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    pub attempts: i32,
+}
+
+pub async fn enqueue(
+    recipient: &str,
+    subject: &str,
+    html: &str,
+    text: &str,
+    db: &mut PgConnection,
+) -> Result<Uuid, HTTPError> {
+    /// Queue an email for delivery by the outbox worker
+    ///
+    /// Use the transaction of the change the email is about, the message is then only sent if
+    /// the change is committed. The worker is woken up on commit.
+    ///
+    /// # Arguments
+    ///  recipient: &str - The address to send to
+    ///  subject: &str - The subject of the email
+    ///  html: &str - The rendered HTML body
+    ///  text: &str - The rendered plain text body
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<Uuid, HTTPError> - The id of the queued message
+    let message_id = Uuid::new_v4();
+
+    sqlx::query!(
+        "INSERT INTO email_outbox (message_id, recipient, subject, html, text)
+         VALUES ($1, $2, $3, $4, $5)",
+        message_id,
+        recipient,
+        subject,
+        html,
+        text
+    )
+    .execute(&mut *db)
+    .await?;
+
+    sqlx::query!("SELECT pg_notify($1, '')", CHANNEL)
+        .execute(db)
+        .await?;
+
+    Ok(message_id)
+}
+
+pub async fn claim_due(
+    limit: i64,
+    lease: time::Duration,
+    db: &PgPool,
+) -> Result<Vec<Delivery>, HTTPError> {
+    /// Claim pending messages that are due and count the attempt
+    ///
+    /// Claimed messages are not due again until the lease ends, so other workers skip them. A
+    /// worker that dies while sending only delays its messages by the lease. Rows locked by a
+    /// concurrent claim are skipped instead of waited for.
+    ///
+    /// # Arguments
+    ///  limit: i64 - The maximum number of messages to claim
+    ///  lease: time::Duration - How long the messages are reserved for this worker
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Vec<Delivery>, HTTPError> - The claimed messages, oldest first
+    let leased_until = OffsetDateTime::now_utc() + lease;
+
+    let rows = sqlx::query!(
+        "UPDATE email_outbox SET attempts = attempts + 1, next_attempt_at = $2
+         WHERE message_id IN (
+             SELECT message_id FROM email_outbox
+             WHERE status = 'pending' AND next_attempt_at <= now()
+             ORDER BY next_attempt_at
+             LIMIT $1
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING message_id, recipient, subject, html, text, attempts",
+        limit,
+        leased_until
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| Delivery {
+            message_id: row.message_id,
+            recipient: row.recipient,
+            subject: row.subject,
+            html: row.html,
+            text: row.text,
+            attempts: row.attempts,
+        })
+        .collect())
+}
+
+pub async fn release(message_ids: &[Uuid], db: &PgPool) -> Result<(), HTTPError> {
+    /// Return claimed messages that were not attempted to the queue, e.g. on shutdown
+    ///
+    /// # Arguments
+    ///  message_ids: &[Uuid] - The ids of the messages
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "UPDATE email_outbox SET attempts = attempts - 1, next_attempt_at = now()
+         WHERE message_id = ANY($1) AND status = 'pending'",
+        message_ids
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete(message_id: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
+    /// Remove a delivered message, its body may contain single-use links
+    ///
+    /// # Arguments
+    ///  message_id: &Uuid - The id of the message
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!("DELETE FROM email_outbox WHERE message_id = $1", message_id)
+        .execute(db)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn record_failure(
+    message_id: &Uuid,
+    error: &str,
+    retry_at: Option<OffsetDateTime>,
+    db: &PgPool,
+) -> Result<(), HTTPError> {
+    /// Record a failed delivery
+    ///
+    /// # Arguments
+    ///  message_id: &Uuid - The id of the message
+    ///  error: &str - Why the delivery failed
+    ///  retry_at: Option<OffsetDateTime> - When to try again, None gives up on the message
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    let status = match retry_at {
+        Some(_) => OutboxStatus::Pending,
+        None => OutboxStatus::Dead,
+    };
+
+    sqlx::query!(
+        "UPDATE email_outbox
+         SET status = $2, last_error = $3, next_attempt_at = coalesce($4, now())
+         WHERE message_id = $1",
+        message_id,
+        status.as_str(),
+        error,
+        retry_at
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list_messages(
+    status: OutboxStatus,
+    limit: i64,
+    offset: i64,
+    db: &PgPool,
+) -> Result<(Vec<OutboxMessage>, i64), HTTPError> {
+    /// List queued messages without their bodies
+    ///
+    /// # Arguments
+    ///  status: OutboxStatus - Only messages with this status
+    ///  limit: i64 - The maximum number of messages to return
+    ///  offset: i64 - The number of messages to skip
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(Vec<OutboxMessage>, i64), HTTPError> - One page of messages, oldest first, and
+    ///  the total number of messages with the status
+    let rows = sqlx::query!(
+        "SELECT message_id, recipient, subject, attempts, next_attempt_at, last_error,
+                created_at, count(*) OVER () AS \"total!\"
+         FROM email_outbox
+         WHERE status = $1
+         ORDER BY created_at
+         LIMIT $2 OFFSET $3",
+        status.as_str(),
+        limit,
+        offset
+    )
+    .fetch_all(db)
+    .await?;
+
+    let total = rows.first().map_or(0, |row| row.total);
+    let messages = rows
+        .into_iter()
+        .map(|row| OutboxMessage {
+            id: row.message_id,
+            recipient: row.recipient,
+            subject: row.subject,
+            status,
+            attempts: row.attempts,
+            next_attempt_at: row.next_attempt_at,
+            last_error: row.last_error,
+            created_at: row.created_at,
+        })
+        .collect();
+
+    Ok((messages, total))
+}
+
+pub async fn requeue(message_id: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
+    /// Give a dead message a fresh set of attempts, starting now
+    ///
+    /// # Arguments
+    ///  message_id: &Uuid - The id of the message
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if there is no dead message with that id
+    let mut tx = db.begin().await?;
+
+    let result = sqlx::query!(
+        "UPDATE email_outbox SET status = 'pending', attempts = 0, next_attempt_at = now()
+         WHERE message_id = $1 AND status = 'dead'",
+        message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(HTTPError::NotFound);
+    }
+
+    sqlx::query!("SELECT pg_notify($1, '')", CHANNEL)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn sweep(dead_before: OffsetDateTime, db: &PgPool) -> Result<u64, HTTPError> {
+    /// Delete dead messages nobody requeued
+    ///
+    /// # Arguments
+    ///  dead_before: OffsetDateTime - Delete messages that last failed before this point
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<u64, HTTPError> - The number of deleted messages
+    let result = sqlx::query!(
+        "DELETE FROM email_outbox
+         WHERE status = 'dead' AND coalesce(updated_at, created_at) < $1",
+        dead_before
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,213 @@
+use crate::http::error::Error as HTTPError;
+use sqlx::{PgConnection, PgPool};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    VerifyEmail,
+    ChangeEmail,
+    CancelEmailChange,
+    ResetPassword,
+}
+
+impl Purpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::VerifyEmail => "verify_email",
+            Self::ChangeEmail => "change_email",
+            Self::CancelEmailChange => "cancel_email_change",
+            Self::ResetPassword => "reset_password",
+        }
+    }
+
+    pub fn lifetime(&self) -> time::Duration {
+        match self {
+            Self::VerifyEmail => time::Duration::days(1),
+            Self::ChangeEmail => time::Duration::hours(1),
+            Self::CancelEmailChange => time::Duration::hours(1),
+            Self::ResetPassword => time::Duration::hours(1),
+        }
+    }
+}
+
+pub async fn create_token(
+    token_id: &Uuid,
+    user_id: &Uuid,
+    purpose: Purpose,
+    token_hash: &str,
+    new_email: Option<&str>,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    /// Store a new email token, it expires after the lifetime of its purpose
+    ///
+    /// # Arguments
+    ///  token_id: &Uuid - The public id of the token, used to look it up
+    ///  user_id: &Uuid - The id of the user the token belongs to
+    ///  purpose: Purpose - What the token may be used for
+    ///  token_hash: &str - The hash of the secret part, the secret itself is never stored
+    ///  new_email: Option<&str> - The requested address, only for email change tokens
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    let expires_at = OffsetDateTime::now_utc() + purpose.lifetime();
+
+    sqlx::query!(
+        "INSERT INTO email_tokens (token_id, user_id, purpose, token_hash, expires_at, new_email)
+         VALUES ($1, $2, $3, $4, $5, $6)",
+        token_id,
+        user_id,
+        purpose.as_str(),
+        token_hash,
+        expires_at,
+        new_email
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn check_token(
+    token_id: &Uuid,
+    purpose: Purpose,
+    token_hash: &str,
+    db: &mut PgConnection,
+) -> Result<Uuid, HTTPError> {
+    /// Check an email token without consuming it, e.g. before slow work that precedes
+    /// `consume_token`
+    ///
+    /// # Arguments
+    ///  token_id: &Uuid - The public id of the token
+    ///  purpose: Purpose - The purpose the token has to have been issued for
+    ///  token_hash: &str - The hash of the presented secret
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<Uuid, HTTPError> - The id of the user the token belongs to, Forbidden if the token
+    ///  is unknown, does not match, expired or was already used
+    let row = sqlx::query!(
+        "SELECT user_id, token_hash FROM email_tokens
+         WHERE token_id = $1 AND purpose = $2 AND consumed_at IS NULL AND expires_at > now()",
+        token_id,
+        purpose.as_str()
+    )
+    .fetch_optional(&mut *db)
+    .await?
+    .ok_or(HTTPError::Forbidden)?;
+
+    if !constant_time_eq::constant_time_eq(row.token_hash.as_bytes(), token_hash.as_bytes()) {
+        log::debug!("Email token {} does not match", token_id);
+        return Err(HTTPError::Forbidden);
+    }
+
+    Ok(row.user_id)
+}
+
+pub async fn consume_token(
+    token_id: &Uuid,
+    purpose: Purpose,
+    token_hash: &str,
+    db: &mut PgConnection,
+) -> Result<Uuid, HTTPError> {
+    /// Check an email token and mark it as consumed
+    ///
+    /// The hash is compared in constant time. Consuming is conditional on the token not being
+    /// consumed yet, so a token can only be used once even if it is submitted concurrently.
+    ///
+    /// # Arguments
+    ///  token_id: &Uuid - The public id of the token
+    ///  purpose: Purpose - The purpose the token has to have been issued for
+    ///  token_hash: &str - The hash of the presented secret
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<Uuid, HTTPError> - The id of the user the token belongs to, Forbidden if the token
+    ///  is unknown, does not match, expired or was already used
+    let user_id = check_token(token_id, purpose, token_hash, &mut *db).await?;
+
+    let result = sqlx::query!(
+        "UPDATE email_tokens SET consumed_at = now() WHERE token_id = $1 AND consumed_at IS NULL",
+        token_id
+    )
+    .execute(db)
+    .await?;
+
+    match result.rows_affected() {
+        1 => Ok(user_id),
+        _ => Err(HTTPError::Forbidden),
+    }
+}
+
+pub async fn get_new_email(token_id: &Uuid, db: &mut PgConnection) -> Result<String, HTTPError> {
+    /// Get the address requested with an email change token
+    ///
+    /// # Arguments
+    ///  token_id: &Uuid - The public id of the token
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<String, HTTPError> - The requested address, NotFound if the token has none
+    sqlx::query!(
+        "SELECT new_email FROM email_tokens WHERE token_id = $1",
+        token_id
+    )
+    .fetch_optional(db)
+    .await?
+    .and_then(|row| row.new_email)
+    .ok_or(HTTPError::NotFound)
+}
+
+pub async fn invalidate_tokens(
+    user_id: &Uuid,
+    purpose: Purpose,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    /// Consume all outstanding tokens of a user for one purpose
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  purpose: Purpose - The purpose of the tokens to invalidate
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "UPDATE email_tokens SET consumed_at = now()
+         WHERE user_id = $1 AND purpose = $2 AND consumed_at IS NULL",
+        user_id,
+        purpose.as_str()
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn sweep(db: &PgPool) -> Result<u64, HTTPError> {
+    /// Delete expired and consumed tokens, then unverified users that have no verification
+    /// token left, i.e. did not verify their address in time
+    ///
+    /// # Arguments
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<u64, HTTPError> - The number of deleted users
+    sqlx::query!("DELETE FROM email_tokens WHERE expires_at < now() OR consumed_at IS NOT NULL")
+        .execute(db)
+        .await?;
+
+    let result = sqlx::query!(
+        "DELETE FROM users u
+         WHERE u.is_verified = false
+           AND NOT EXISTS (
+               SELECT 1 FROM email_tokens t
+               WHERE t.user_id = u.user_id AND t.purpose = 'verify_email'
+           )"
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,46 @@
+use crate::crud::MIGRATOR;
+use crate::http::error::Error as HTTPError;
+use sqlx::{PgPool, migrate::Migrate};
+
+pub async fn ping(db: &PgPool) -> Result<(), HTTPError> {
+    /// Run a trivial query, proving a connection can be acquired and the server answers
+    ///
+    /// # Arguments
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the query
+    sqlx::query!("SELECT 1 AS one").fetch_one(db).await?;
+
+    Ok(())
+}
+
+pub async fn pending_migrations(db: &PgPool) -> Result<usize, HTTPError> {
+    /// Count the embedded migrations the database has not successfully applied, e.g. because
+    /// a newer instance is rolled out before the migration ran
+    ///
+    /// # Arguments
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<usize, HTTPError> - The number of pending migrations
+    let mut conn = db.acquire().await?;
+    let applied = conn
+        .list_applied_migrations()
+        .await
+        .map_err(|e| HTTPError::Anyhow(e.into()))?;
+    // A migration that failed halfway is listed as applied
+    let failed = conn
+        .dirty_version()
+        .await
+        .map_err(|e| HTTPError::Anyhow(e.into()))?;
+
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| {
+            Some(migration.version) == failed
+                || !applied.iter().any(|m| m.version == migration.version)
+        })
+        .count())
+}
@@ -0,0 +1,190 @@
+use crate::http::error::Error as HTTPError;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Account,
+    Ip,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Account => "account",
+            Self::Ip => "ip",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    pub scope: Scope,
+    pub failures: i32,
+    pub last_failure: OffsetDateTime,
+    pub locked_until: Option<OffsetDateTime>,
+}
+
+pub async fn get_throttles(
+    account: &str,
+    ip: Option<&str>,
+    db: &PgPool,
+) -> Result<Vec<Throttle>, HTTPError> {
+    /// Get the failed login state of an account and of a client address
+    ///
+    /// # Arguments
+    ///  account: &str - The lowercased username
+    ///  ip: Option<&str> - The address of the client, if known
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Vec<Throttle>, HTTPError> - Up to one entry per scope, none if nothing failed yet
+    let rows = sqlx::query!(
+        "SELECT scope, failures, last_failure, locked_until FROM login_throttles
+         WHERE (scope = 'account' AND subject = $1) OR (scope = 'ip' AND subject = $2)",
+        account,
+        ip
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| Throttle {
+            scope: match row.scope.as_str() {
+                "account" => Scope::Account,
+                _ => Scope::Ip,
+            },
+            failures: row.failures,
+            last_failure: row.last_failure,
+            locked_until: row.locked_until,
+        })
+        .collect())
+}
+
+pub async fn record_failure(
+    scope: Scope,
+    subject: &str,
+    forget_before: OffsetDateTime,
+    db: &PgPool,
+) -> Result<i32, HTTPError> {
+    /// Count a failed login
+    ///
+    /// # Arguments
+    ///  scope: Scope - Whether the subject is an account or a client address
+    ///  subject: &str - The lowercased username or the address
+    ///  forget_before: OffsetDateTime - Earlier failures are forgotten and counting restarts
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<i32, HTTPError> - The number of failures including this one
+    let row = sqlx::query!(
+        "INSERT INTO login_throttles (scope, subject, failures, last_failure)
+         VALUES ($1, $2, 1, now())
+         ON CONFLICT (scope, subject) DO UPDATE SET
+             failures = CASE WHEN login_throttles.last_failure < $3 THEN 1
+                             ELSE login_throttles.failures + 1 END,
+             last_failure = now()
+         RETURNING failures",
+        scope.as_str(),
+        subject,
+        forget_before
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.failures)
+}
+
+pub async fn lock(
+    scope: Scope,
+    subject: &str,
+    until: OffsetDateTime,
+    db: &PgPool,
+) -> Result<(), HTTPError> {
+    /// Lock an account or a client address, counting failures restarts after the lockout
+    ///
+    /// # Arguments
+    ///  scope: Scope - Whether the subject is an account or a client address
+    ///  subject: &str - The lowercased username or the address
+    ///  until: OffsetDateTime - When logins are allowed again
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "UPDATE login_throttles SET failures = 0, locked_until = $3
+         WHERE scope = $1 AND subject = $2",
+        scope.as_str(),
+        subject,
+        until
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_locked_until(
+    account: &str,
+    db: &PgPool,
+) -> Result<Option<OffsetDateTime>, HTTPError> {
+    /// Get the end of the current lockout of an account
+    ///
+    /// # Arguments
+    ///  account: &str - The lowercased username
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Option<OffsetDateTime>, HTTPError> - None if the account is not locked
+    let row = sqlx::query!(
+        "SELECT locked_until FROM login_throttles
+         WHERE scope = 'account' AND subject = $1 AND locked_until > now()",
+        account
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.and_then(|row| row.locked_until))
+}
+
+pub async fn clear(scope: Scope, subject: &str, db: &PgPool) -> Result<(), HTTPError> {
+    /// Forget all failures and lift the lockout of an account or a client address
+    ///
+    /// # Arguments
+    ///  scope: Scope - Whether the subject is an account or a client address
+    ///  subject: &str - The lowercased username or the address
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "DELETE FROM login_throttles WHERE scope = $1 AND subject = $2",
+        scope.as_str(),
+        subject
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn sweep(forget_before: OffsetDateTime, db: &PgPool) -> Result<u64, HTTPError> {
+    /// Delete entries whose failures are forgotten and that are not locked anymore
+    ///
+    /// # Arguments
+    ///  forget_before: OffsetDateTime - Failures before this are not counted anymore
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<u64, HTTPError> - The number of deleted entries
+    let result = sqlx::query!(
+        "DELETE FROM login_throttles
+         WHERE last_failure < $1 AND (locked_until IS NULL OR locked_until < now())",
+        forget_before
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
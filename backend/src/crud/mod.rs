@@ -1,2 +1,25 @@
 #[allow(unused_doc_comments)]
+pub mod admin;
+#[allow(unused_doc_comments)]
+pub mod email_outbox;
+#[allow(unused_doc_comments)]
+pub mod email_token;
+#[allow(unused_doc_comments)]
+pub mod health;
+#[allow(unused_doc_comments)]
+pub mod login_throttle;
+#[allow(unused_doc_comments)]
+pub mod rate_limit;
+#[allow(unused_doc_comments)]
+pub mod refresh_token;
+#[allow(unused_doc_comments)]
+pub mod role;
+#[allow(unused_doc_comments)]
+pub mod session;
+#[allow(unused_doc_comments)]
+pub mod two_factor;
+#[allow(unused_doc_comments)]
 pub mod user;
+
+/// The migrations in `migrations/`, embedded at compile time and applied at startup
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
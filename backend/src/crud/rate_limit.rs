@@ -0,0 +1,73 @@
+use crate::http::error::Error as HTTPError;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+pub async fn acquire(
+    key: &str,
+    burst: f64,
+    refill_per_second: f64,
+    db: &PgPool,
+) -> Result<(bool, f64), HTTPError> {
+    /// Take a token from a bucket, refilling it for the time since it was last used
+    ///
+    /// The update is skipped when less than one token is left, so concurrent requests on other
+    /// instances never take more tokens than there are.
+    ///
+    /// # Arguments
+    ///  key: &str - The key of the bucket
+    ///  burst: f64 - The capacity of the bucket, new buckets start full
+    ///  refill_per_second: f64 - How many tokens are added per second
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(bool, f64), HTTPError> - Whether a token was taken and the tokens left
+    let taken = sqlx::query!(
+        r#"INSERT INTO rate_limit_buckets AS b (key, tokens, updated_at)
+           VALUES ($1, $2::float8 - 1, now())
+           ON CONFLICT (key) DO UPDATE SET
+               tokens = least($2::float8, b.tokens + extract(epoch FROM now() - b.updated_at)::float8 * $3::float8) - 1,
+               updated_at = now()
+           WHERE least($2::float8, b.tokens + extract(epoch FROM now() - b.updated_at)::float8 * $3::float8) >= 1
+           RETURNING tokens"#,
+        key,
+        burst,
+        refill_per_second
+    )
+    .fetch_optional(db)
+    .await?;
+
+    if let Some(row) = taken {
+        return Ok((true, row.tokens));
+    }
+
+    let row = sqlx::query!(
+        r#"SELECT least($2::float8, tokens + extract(epoch FROM now() - updated_at)::float8 * $3::float8) AS "tokens!"
+           FROM rate_limit_buckets WHERE key = $1"#,
+        key,
+        burst,
+        refill_per_second
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok((false, row.tokens))
+}
+
+pub async fn sweep(updated_before: OffsetDateTime, db: &PgPool) -> Result<u64, HTTPError> {
+    /// Delete buckets that have not been used for a while, they are full again anyway
+    ///
+    /// # Arguments
+    ///  updated_before: OffsetDateTime - Buckets last used before this are deleted
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<u64, HTTPError> - The number of deleted buckets
+    let result = sqlx::query!(
+        "DELETE FROM rate_limit_buckets WHERE updated_at < $1",
+        updated_before
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,112 @@
+use crate::http::error::Error as HTTPError;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+pub async fn create_token(
+    session_id: &Uuid,
+    token_hash: &str,
+    expires_at: OffsetDateTime,
+    db: &PgPool,
+) -> Result<(), HTTPError> {
+    /// Store the first refresh token of a session
+    ///
+    /// # Arguments
+    ///  session_id: &Uuid - The session, which is also the token family
+    ///  token_hash: &str - The hash of the token, the token itself is never stored
+    ///  expires_at: OffsetDateTime - Expiry of the whole token family
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "INSERT INTO refresh_tokens (token_hash, session_id, expires_at) VALUES ($1, $2, $3)",
+        token_hash,
+        session_id,
+        expires_at
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn rotate_token(
+    token_hash: &str,
+    new_token_hash: &str,
+    db: &PgPool,
+) -> Result<(Uuid, Uuid), HTTPError> {
+    /// Exchange a refresh token for a new one of the same family
+    ///
+    /// The new token keeps the expiry of the old one, so refreshing cannot extend a session
+    /// beyond the lifetime it got at login. If the presented token was already rotated it has
+    /// been used twice, which means it leaked, and the whole family is revoked.
+    ///
+    /// # Arguments
+    ///  token_hash: &str - The hash of the presented token
+    ///  new_token_hash: &str - The hash of the token replacing it
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(Uuid, Uuid), HTTPError> - The user id and session id, Unauthorized if the token
+    ///  is unknown, expired, reused or its session was revoked
+    let mut tx = db.begin().await?;
+
+    let row = sqlx::query!(
+        "SELECT r.session_id, r.expires_at, r.rotated_at, s.user_id, s.revoked_at
+         FROM refresh_tokens r JOIN sessions s USING (session_id)
+         WHERE r.token_hash = $1
+         FOR UPDATE OF r",
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(HTTPError::Unauthorized)?;
+
+    if row.rotated_at.is_some() {
+        log::warn!(
+            "Refresh token reuse detected, revoking session {}",
+            row.session_id
+        );
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = now() WHERE session_id = $1 AND revoked_at IS NULL",
+            row.session_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        return Err(HTTPError::Unauthorized);
+    }
+
+    if row.revoked_at.is_some() || row.expires_at <= OffsetDateTime::now_utc() {
+        return Err(HTTPError::Unauthorized);
+    }
+
+    sqlx::query!(
+        "UPDATE refresh_tokens SET rotated_at = now() WHERE token_hash = $1",
+        token_hash
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO refresh_tokens (token_hash, session_id, expires_at) VALUES ($1, $2, $3)",
+        new_token_hash,
+        row.session_id,
+        row.expires_at
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE sessions SET last_seen = now() WHERE session_id = $1",
+        row.session_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok((row.user_id, row.session_id))
+}
@@ -0,0 +1,189 @@
+use crate::http::error::{Error as HTTPError, ResultExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub async fn get_roles(user_id: &Uuid, db: &PgPool) -> Result<Vec<String>, HTTPError> {
+    /// Get the names of all roles of a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Vec<String>, HTTPError> - The role names, sorted
+    let rows = sqlx::query!(
+        "SELECT role FROM user_roles WHERE user_id = $1 ORDER BY role",
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| row.role).collect())
+}
+
+pub async fn get_permissions(user_id: &Uuid, db: &PgPool) -> Result<Vec<String>, HTTPError> {
+    /// Get the names of all permissions a user has through any of their roles
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Vec<String>, HTTPError> - The permission names, sorted and without duplicates
+    let rows = sqlx::query!(
+        "SELECT DISTINCT rp.permission FROM user_roles ur
+         JOIN role_permissions rp ON rp.role = ur.role
+         WHERE ur.user_id = $1
+         ORDER BY rp.permission",
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| row.permission).collect())
+}
+
+pub async fn has_role(user_id: &Uuid, role: &str, db: &PgPool) -> Result<bool, HTTPError> {
+    /// Check if a user has a role
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  role: &str - The name of the role
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<bool, HTTPError> - True if the role was granted to the user
+    let row = sqlx::query!(
+        "SELECT EXISTS (SELECT 1 FROM user_roles WHERE user_id = $1 AND role = $2) AS granted",
+        user_id,
+        role
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.granted.unwrap_or(false))
+}
+
+pub async fn has_permission(
+    user_id: &Uuid,
+    permission: &str,
+    db: &PgPool,
+) -> Result<bool, HTTPError> {
+    /// Check if a user has a permission through any of their roles
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  permission: &str - The name of the permission
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<bool, HTTPError> - True if one of the user's roles grants the permission
+    let row = sqlx::query!(
+        "SELECT EXISTS (
+             SELECT 1 FROM user_roles ur
+             JOIN role_permissions rp ON rp.role = ur.role
+             WHERE ur.user_id = $1 AND rp.permission = $2
+         ) AS granted",
+        user_id,
+        permission
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.granted.unwrap_or(false))
+}
+
+pub async fn grant_role(user_id: &Uuid, role: &str, db: &PgPool) -> Result<(), HTTPError> {
+    /// Grant a role to a user, granting a role twice is not an error
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  role: &str - The name of the role
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if the user or the role does not exist
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        user_id,
+        role
+    )
+    .execute(db)
+    .await
+    .on_constraint("user_roles_user_id_fkey", |_| HTTPError::NotFound)
+    .on_constraint("user_roles_role_fkey", |_| HTTPError::NotFound)?;
+
+    Ok(())
+}
+
+pub async fn grant_role_by_username(
+    username: &str,
+    role: &str,
+    db: &PgPool,
+) -> Result<(), HTTPError> {
+    /// Grant a role to a user identified by their username
+    ///
+    /// # Arguments
+    ///  username: &str - The username of the user
+    ///  role: &str - The name of the role
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if the user or the role does not exist
+    let result = sqlx::query!(
+        "INSERT INTO user_roles (user_id, role)
+         SELECT user_id, $2 FROM users WHERE username = $1
+         ON CONFLICT DO NOTHING",
+        username,
+        role
+    )
+    .execute(db)
+    .await
+    .on_constraint("user_roles_role_fkey", |_| HTTPError::NotFound)?;
+
+    // Zero rows either means the user does not exist or already has the role
+    if result.rows_affected() == 0 && !has_role_by_username(username, role, db).await? {
+        return Err(HTTPError::NotFound);
+    }
+
+    Ok(())
+}
+
+async fn has_role_by_username(username: &str, role: &str, db: &PgPool) -> Result<bool, HTTPError> {
+    let row = sqlx::query!(
+        "SELECT EXISTS (
+             SELECT 1 FROM user_roles ur JOIN users u USING (user_id)
+             WHERE u.username = $1 AND ur.role = $2
+         ) AS granted",
+        username,
+        role
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.granted.unwrap_or(false))
+}
+
+pub async fn revoke_role(user_id: &Uuid, role: &str, db: &PgPool) -> Result<(), HTTPError> {
+    /// Revoke a role from a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  role: &str - The name of the role
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if the user did not have the role
+    let result = sqlx::query!(
+        "DELETE FROM user_roles WHERE user_id = $1 AND role = $2",
+        user_id,
+        role
+    )
+    .execute(db)
+    .await?;
+
+    match result.rows_affected() {
+        0 => Err(HTTPError::NotFound),
+        _ => Ok(()),
+    }
+}
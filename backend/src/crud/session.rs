@@ -0,0 +1,165 @@
+use crate::{http::error::Error as HTTPError, schemas::sessions::Session};
+use sqlx::{PgConnection, PgPool};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+pub async fn create_session(
+    user_id: &Uuid,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+    db: &PgPool,
+) -> Result<Uuid, HTTPError> {
+    /// Create a new session for a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user the session belongs to
+    ///  user_agent: Option<&str> - The user agent of the client that logged in
+    ///  ip: Option<&str> - The ip address of the client that logged in
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Uuid, HTTPError> - The id of the new session
+    let session_id = Uuid::new_v4();
+
+    sqlx::query!(
+        "INSERT INTO sessions (session_id, user_id, user_agent, ip) VALUES ($1, $2, $3, $4)",
+        session_id,
+        user_id,
+        user_agent,
+        ip
+    )
+    .execute(db)
+    .await?;
+
+    Ok(session_id)
+}
+
+pub async fn get_last_seen(
+    session_id: &Uuid,
+    user_id: &Uuid,
+    db: &PgPool,
+) -> Result<Option<(OffsetDateTime, bool)>, HTTPError> {
+    /// Get the last activity of a session that has not been revoked
+    ///
+    /// # Arguments
+    ///  session_id: &Uuid - The id of the session
+    ///  user_id: &Uuid - The id of the user the session has to belong to
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Option<(OffsetDateTime, bool)>, HTTPError> - The last activity and whether the
+    ///  user is suspended, None if the session does not exist or was revoked
+    let row = sqlx::query!(
+        "SELECT s.last_seen, u.suspended_at FROM sessions s JOIN users u USING (user_id)
+         WHERE s.session_id = $1 AND s.user_id = $2 AND s.revoked_at IS NULL",
+        session_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| (row.last_seen, row.suspended_at.is_some())))
+}
+
+pub async fn touch_session(session_id: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
+    /// Update the last activity of a session
+    ///
+    /// # Arguments
+    ///  session_id: &Uuid - The id of the session
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "UPDATE sessions SET last_seen = now() WHERE session_id = $1",
+        session_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list_sessions(
+    user_id: &Uuid,
+    current_session: &Uuid,
+    db: &PgPool,
+) -> Result<Vec<Session>, HTTPError> {
+    /// List all sessions of a user that have not been revoked
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  current_session: &Uuid - The session of the caller, flagged as current in the result
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Vec<Session>, HTTPError> - The active sessions, most recently used first
+    let rows = sqlx::query!(
+        "SELECT session_id, created_at, last_seen, user_agent, ip FROM sessions
+         WHERE user_id = $1 AND revoked_at IS NULL
+         ORDER BY last_seen DESC",
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| Session {
+            id: row.session_id,
+            created_at: row.created_at,
+            last_seen: row.last_seen,
+            user_agent: row.user_agent,
+            ip: row.ip,
+            current: row.session_id == *current_session,
+        })
+        .collect())
+}
+
+pub async fn revoke_session(
+    session_id: &Uuid,
+    user_id: &Uuid,
+    db: &PgPool,
+) -> Result<(), HTTPError> {
+    /// Revoke a single session of a user
+    ///
+    /// # Arguments
+    ///  session_id: &Uuid - The id of the session
+    ///  user_id: &Uuid - The id of the user the session has to belong to
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - NotFound if the user has no such active session
+    let result = sqlx::query!(
+        "UPDATE sessions SET revoked_at = now()
+         WHERE session_id = $1 AND user_id = $2 AND revoked_at IS NULL",
+        session_id,
+        user_id
+    )
+    .execute(db)
+    .await?;
+
+    match result.rows_affected() {
+        0 => Err(HTTPError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+pub async fn revoke_all_sessions(user_id: &Uuid, db: &mut PgConnection) -> Result<(), HTTPError> {
+    /// Revoke all sessions of a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "UPDATE sessions SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+        user_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
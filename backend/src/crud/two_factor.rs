@@ -0,0 +1,190 @@
+use crate::http::error::Error as HTTPError;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub async fn set_pending_secret(
+    user_id: &Uuid,
+    secret: &str,
+    db: &PgPool,
+) -> Result<(), HTTPError> {
+    /// Store a new, not yet confirmed TOTP secret for a user
+    ///
+    /// An unconfirmed secret from an abandoned setup is replaced, a confirmed one is not.
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  secret: &str - The base32 encoded secret
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - Conflict if two-factor authentication is already enabled
+    let result = sqlx::query!(
+        "INSERT INTO totp_secrets (user_id, secret) VALUES ($1, $2)
+         ON CONFLICT (user_id) DO UPDATE SET secret = $2, created_at = now()
+         WHERE totp_secrets.confirmed_at IS NULL",
+        user_id,
+        secret
+    )
+    .execute(db)
+    .await?;
+
+    match result.rows_affected() {
+        0 => Err(HTTPError::Conflict),
+        _ => Ok(()),
+    }
+}
+
+pub async fn get_secret(user_id: &Uuid, db: &PgPool) -> Result<Option<(String, bool)>, HTTPError> {
+    /// Get the TOTP secret of a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<Option<(String, bool)>, HTTPError> - The base32 encoded secret and whether it is
+    ///  confirmed, None if the user never started a setup
+    let row = sqlx::query!(
+        "SELECT secret, confirmed_at FROM totp_secrets WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| (row.secret, row.confirmed_at.is_some())))
+}
+
+pub async fn is_enabled(user_id: &Uuid, db: &PgPool) -> Result<bool, HTTPError> {
+    /// Check if a user has confirmed two-factor authentication
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<bool, HTTPError> - True if a second factor is required at login
+    let row = sqlx::query!(
+        "SELECT EXISTS (SELECT 1 FROM totp_secrets WHERE user_id = $1 AND confirmed_at IS NOT NULL) AS enabled",
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.enabled.unwrap_or(false))
+}
+
+pub async fn confirm_secret(
+    user_id: &Uuid,
+    step: i64,
+    recovery_code_hashes: &[String],
+    db: &PgPool,
+) -> Result<(), HTTPError> {
+    /// Enable two-factor authentication and store a fresh set of recovery codes
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  step: i64 - The time step of the code that confirmed the setup
+    ///  recovery_code_hashes: &[String] - Hashes of the new recovery codes
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        "UPDATE totp_secrets SET confirmed_at = now(), last_used_step = $2 WHERE user_id = $1",
+        user_id,
+        step
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, unnest($2::text[])",
+        user_id,
+        recovery_code_hashes
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn use_step(user_id: &Uuid, step: i64, db: &PgPool) -> Result<bool, HTTPError> {
+    /// Record the time step of an accepted TOTP code
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  step: i64 - The time step of the accepted code
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<bool, HTTPError> - False if a code of this or a later step was already used,
+    ///  i.e. the code is replayed
+    let result = sqlx::query!(
+        "UPDATE totp_secrets SET last_used_step = $2
+         WHERE user_id = $1 AND confirmed_at IS NOT NULL
+           AND (last_used_step IS NULL OR last_used_step < $2)",
+        user_id,
+        step
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn consume_recovery_code(
+    user_id: &Uuid,
+    code_hash: &str,
+    db: &PgPool,
+) -> Result<bool, HTTPError> {
+    /// Use up a recovery code
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  code_hash: &str - The hash of the presented code
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<bool, HTTPError> - True if the code was valid and unused
+    let result = sqlx::query!(
+        "UPDATE recovery_codes SET used_at = now()
+         WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+        user_id,
+        code_hash
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn disable(user_id: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
+    /// Remove the TOTP secret and all recovery codes of a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    let mut tx = db.begin().await?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM totp_secrets WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
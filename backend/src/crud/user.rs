@@ -1,53 +1,84 @@
-use std::sync::Arc;
-
 use crate::{
-    http::{error::Error as HTTPError, utils::random_string, utils::send_verification, AppState},
+    http::error::{Error as HTTPError, ResultExt},
     schemas::users::User,
 };
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
-pub async fn get_verification_token(username: &str, db: &PgPool) -> Result<String, HTTPError> {
-    /// Get the verification token of a user
+pub async fn claim_verification_resend(
+    email: &str,
+    sent_before: OffsetDateTime,
+    db: &mut PgConnection,
+) -> Result<Option<(Uuid, Option<String>)>, HTTPError> {
+    /// Claim the right to resend the verification email of an unverified user
+    ///
+    /// Checking and updating happens in one statement, so concurrent requests cannot send more
+    /// than one email per interval.
     ///
     /// # Arguments
-    ///  username: &str - The username of the user
-    ///  db: PgPool - The database connection pool
+    ///  email: &str - The email address of the user
+    ///  sent_before: OffsetDateTime - Only claim if the last email was sent before this point
+    ///  db: &mut PgConnection - A connection or transaction
     ///
     /// # Returns
-    ///  Result<String, HTTPError> - The verification token if found, an error otherwise
-    let result = sqlx::query!(
-        "SELECT verification_token FROM users WHERE username = $1",
-        username
+    ///  Result<Option<(Uuid, Option<String>)>, HTTPError> - The id and locale of the user, None
+    ///  if there is no such unverified user or the last email is too recent
+    let row = sqlx::query!(
+        "UPDATE users SET verification_sent_at = now()
+         WHERE email = $1 AND is_verified = false
+           AND (verification_sent_at IS NULL OR verification_sent_at < $2)
+         RETURNING user_id, locale",
+        email,
+        sent_before
     )
-    .fetch_one(db)
-    .await
-    .map_err(HTTPError::from)?;
+    .fetch_optional(db)
+    .await?;
 
-    match result.verification_token {
-        Some(token) => Ok(token),
-        None => Err(HTTPError::NotFound),
-    }
+    Ok(row.map(|row| (row.user_id, row.locale)))
 }
 
-pub async fn verify_user(username: &str, db: &PgPool) -> Result<(), HTTPError> {
-    /// Verify a user
+pub async fn is_verified(id: &Uuid, db: &PgPool) -> Result<bool, HTTPError> {
+    /// Check if a user has verified their email address
     ///
     /// # Arguments
-    ///  username: &str - The username of the user
+    ///  id: &Uuid - The user id
     ///  db: PgPool - The database connection pool
     ///
     /// # Returns
-    ///  Result<(), HTTPError> - The result of the operation
+    ///  Result<bool, HTTPError> - True if the email address is verified
+    let row = sqlx::query!("SELECT is_verified FROM users WHERE user_id = $1", id)
+        .fetch_one(db)
+        .await?;
+
+    Ok(row.is_verified)
+}
+
+pub async fn verify_user(id: &Uuid, db: &mut PgConnection) -> Result<User, HTTPError> {
+    /// Verify a user
+    ///
+    /// # Arguments
+    ///  id: &Uuid - The user id
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<User, HTTPError> - The verified user
     let result = sqlx::query!(
-        "UPDATE users SET is_verified = true WHERE username = $1",
-        username
+        "UPDATE users SET is_verified = true WHERE user_id = $1
+         RETURNING user_id, username, email, is_verified, locale",
+        id
     )
-    .execute(db)
+    .fetch_one(db)
     .await;
 
     match result {
-        Ok(_) => Ok(()),
+        Ok(row) => Ok(User {
+            id: row.user_id,
+            username: row.username,
+            email: row.email,
+            verified: row.is_verified,
+            locale: row.locale,
+        }),
         Err(e) => {
             log::error!("Error verifying user: {:?}", e);
             Err(HTTPError::from(e))
@@ -58,7 +89,7 @@ pub async fn verify_user(username: &str, db: &PgPool) -> Result<(), HTTPError> {
 pub async fn update_password(
     id: &Uuid,
     password_hash: &str,
-    db: &PgPool,
+    db: &mut PgConnection,
 ) -> Result<bool, HTTPError> {
     let result = sqlx::query!(
         "update users set password_hash = $1 where user_id = $2;",
@@ -96,30 +127,78 @@ pub async fn get_user_by_id(id: &Uuid, db: &PgPool) -> Result<User, HTTPError> {
             username: row.username,
             email: row.email,
             verified: row.is_verified,
+            locale: row.locale,
         }),
         Err(e) => Err(HTTPError::from(e)),
     }
 }
 
-pub async fn check_username(username: &str, db: &PgPool) -> bool {
-    /// Check if the username exists in the DB
+pub async fn get_user_by_email(email: &str, db: &PgPool) -> Result<User, HTTPError> {
+    /// Get a user by their email address
     ///
     /// # Arguments
-    ///  username: &str - The username to check
+    ///  email: &str - The email address of the user
     ///  db: PgPool - The database connection pool
     ///
     /// # Returns
-    ///  bool - True if the username exists, false otherwise
-    let result = sqlx::query!("SELECT username FROM users WHERE username = $1", username)
+    ///  Result<User, HTTPError> - The user if found, an error otherwise
+    let result = sqlx::query!("SELECT * FROM users WHERE email = $1", email)
         .fetch_one(db)
         .await;
 
     match result {
-        Ok(_) => true,
-        Err(_) => false,
+        Ok(row) => Ok(User {
+            id: row.user_id,
+            username: row.username,
+            email: row.email,
+            verified: row.is_verified,
+            locale: row.locale,
+        }),
+        Err(e) => Err(HTTPError::from(e)),
     }
 }
 
+pub async fn get_user_by_username(username: &str, db: &PgPool) -> Result<User, HTTPError> {
+    /// Get a user by their username
+    ///
+    /// # Arguments
+    ///  username: &str - The username of the user
+    ///  db: PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<User, HTTPError> - The user if found, an error otherwise
+    let result = sqlx::query!("SELECT * FROM users WHERE username = $1", username)
+        .fetch_one(db)
+        .await;
+
+    match result {
+        Ok(row) => Ok(User {
+            id: row.user_id,
+            username: row.username,
+            email: row.email,
+            verified: row.is_verified,
+            locale: row.locale,
+        }),
+        Err(e) => Err(HTTPError::from(e)),
+    }
+}
+
+pub async fn is_suspended(id: &Uuid, db: &PgPool) -> Result<bool, HTTPError> {
+    /// Check if a user is suspended
+    ///
+    /// # Arguments
+    ///  id: &Uuid - The user id
+    ///  db: PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<bool, HTTPError> - True if an admin suspended the user
+    let row = sqlx::query!("SELECT suspended_at FROM users WHERE user_id = $1", id)
+        .fetch_one(db)
+        .await?;
+
+    Ok(row.suspended_at.is_some())
+}
+
 pub async fn check_email(email: &str, db: &PgPool) -> bool {
     /// Check if the email exists in the DB
     ///
@@ -133,59 +212,54 @@ pub async fn check_email(email: &str, db: &PgPool) -> bool {
         .fetch_one(db)
         .await;
 
-    match result {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    result.is_ok()
 }
 
 pub async fn create_user(
     username: &str,
     email: &str,
     password_hash: &str,
-    state: Arc<AppState>,
-) -> Result<(), HTTPError> {
+    locale: Option<&str>,
+    db: &mut PgConnection,
+) -> Result<Uuid, HTTPError> {
     /// Create a new user in DB
     ///
+    /// Use a transaction that also queues the verification email, so no account is left
+    /// without one.
+    ///
     /// # Arguments
     ///  username: &str - The username of the user
     ///  email: &str - The email of the user
     ///  password_hash: &str - The password hash of the user
+    ///  locale: Option<&str> - The language of the emails to the user
+    ///  db: &mut PgConnection - A connection or transaction
     ///
     /// # Returns
-    ///  Result<(), HTTPError> - The result of the operation
-    let db = &state.db;
-
+    ///  Result<Uuid, HTTPError> - The id of the new user, Taken with the field if the username
+    ///  or email address is already used by another account, compared case insensitively
     let uid = Uuid::new_v4();
-    let verification_token = random_string(8);
 
-    if check_username(username, db).await || check_email(email, db).await {
-        return Err(HTTPError::Unauthorized);
-    }
-
-    let result = sqlx::query!(
-        "INSERT INTO users (user_id, username, email, password_hash, verification_token, is_verified) VALUES ($1, $2, $3, $4, $5 , true)",
+    // Every new account starts with the `user` role. Duplicates are left to the unique
+    // constraints, a check before inserting would race with concurrent registrations.
+    sqlx::query!(
+        "WITH new_user AS (
+             INSERT INTO users (user_id, username, email, password_hash, locale, verification_sent_at)
+             VALUES ($1, $2, $3, $4, $5, now())
+             RETURNING user_id
+         )
+         INSERT INTO user_roles (user_id, role) SELECT user_id, 'user' FROM new_user",
         uid,
         username,
         email,
         password_hash,
-        verification_token
-    ).execute(db).await;
-
-    tokio::spawn(send_verification(
-        email.to_string(),
-        username.to_string(),
-        verification_token,
-        state,
-    ));
+        locale
+    )
+    .execute(db)
+    .await
+    .on_constraint("users_username_key", |_| HTTPError::Taken { field: "username" })
+    .on_constraint("users_email_key", |_| HTTPError::Taken { field: "email" })?;
 
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            log::error!("Error creating user: {}", e);
-            Err(HTTPError::Unauthorized)
-        }
-    }
+    Ok(uid)
 }
 
 pub async fn delete_user(uid: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
@@ -206,6 +280,87 @@ pub async fn delete_user(uid: &Uuid, db: &PgPool) -> Result<(), HTTPError> {
         Err(e) => Err(HTTPError::from(e)),
     }
 }
+
+pub async fn update_locale(id: &Uuid, locale: Option<&str>, db: &PgPool) -> Result<(), HTTPError> {
+    /// Set the language of the emails to a user
+    ///
+    /// # Arguments
+    ///  id: &Uuid - The user id
+    ///  locale: Option<&str> - A language tag, None to follow the Accept-Language of requests
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - The result of the operation
+    sqlx::query!(
+        "UPDATE users SET locale = $2 WHERE user_id = $1",
+        id,
+        locale
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn update_email(
+    user_id: &Uuid,
+    email: &str,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    /// Replace the email address of a user
+    ///
+    /// # Arguments
+    ///  user_id: &Uuid - The id of the user
+    ///  email: &str - The new, already confirmed, address
+    ///  db: &mut PgConnection - A connection or transaction
+    ///
+    /// # Returns
+    ///  Result<(), HTTPError> - Taken if another account uses the address, compared case
+    ///  insensitively
+    let result = sqlx::query!(
+        "UPDATE users SET email = $2 WHERE user_id = $1",
+        user_id,
+        email
+    )
+    .execute(db)
+    .await
+    .on_constraint("users_email_key", |_| HTTPError::Taken { field: "email" })?;
+
+    match result.rows_affected() {
+        0 => Err(HTTPError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+pub async fn replace_password_hash(
+    id: &Uuid,
+    old_hash: &str,
+    new_hash: &str,
+    db: &PgPool,
+) -> Result<bool, HTTPError> {
+    /// Replace a password hash by a new hash of the same password
+    ///
+    /// # Arguments
+    ///  id: &Uuid - The user id
+    ///  old_hash: &str - The hash the new one was computed from
+    ///  new_hash: &str - The new hash
+    ///  db: &PgPool - The database connection pool
+    ///
+    /// # Returns
+    ///  Result<bool, HTTPError> - False if the password was changed since the old hash was read,
+    ///  the newer password is kept then
+    let result = sqlx::query!(
+        "UPDATE users SET password_hash = $3 WHERE user_id = $1 AND password_hash = $2",
+        id,
+        old_hash,
+        new_hash
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
 pub async fn get_hash(username: &str, db: &PgPool) -> Result<(Uuid, String), sqlx::Error> {
     /// Get the user's id and password hash from the DB
     ///
@@ -1,47 +1,143 @@
 // External Crates
+use crate::config::Config;
 use crate::crud;
-use argon2::{
-    Argon2,
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
-};
+use crate::crud::login_throttle::{Scope, Throttle};
+use crate::schemas::validation::{self, Validate};
 use axum::{
-    extract::FromRequestParts,
-    http::{header::COOKIE, request::Parts},
+    Json,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{
+        header::{ACCEPT_LANGUAGE, COOKIE, USER_AGENT},
+        request::Parts,
+    },
 };
 use axum_extra::extract::cookie::CookieJar;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use serde::de::DeserializeOwned;
 use sqlx::PgPool;
+use std::{convert::Infallible, marker::PhantomData, sync::Arc};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
 // Internal Modules
-use crate::http::{AppState, error::Error as HTTPError};
+use crate::http::{AppState, error::Error as HTTPError, forwarded, utils};
+use crate::password::Verification;
+use crate::totp;
 
-const DEFAULT_SESSION_DURATION: time::Duration = time::Duration::weeks(1);
+// Only write `last_seen` of a session once per interval instead of on every request
+const SESSION_TOUCH_INTERVAL: time::Duration = time::Duration::minutes(1);
 
 pub const DEFAULT_AUTH: &str = "jwt";
 
+pub const DEFAULT_REFRESH: &str = "refresh";
+
+// The refresh cookie is only sent to the endpoint that consumes it
+pub const REFRESH_PATH: &str = "/token/renew";
+
+pub const DEFAULT_MFA: &str = "mfa";
+
+pub const MFA_PATH: &str = "/token/mfa";
+
+// Time a user has to enter the second factor after the password was accepted
+pub const MFA_PENDING_DURATION: time::Duration = time::Duration::minutes(5);
+
 pub struct AuthUser {
     pub user_id: Uuid,
+    pub session_id: Uuid,
 }
 
 // Use in handler if auth is optional
 pub struct OptionalAuthUser(pub Option<AuthUser>);
 
+// A JSON body that was normalized and passed `Validate`, use instead of `Json` for request bodies
+pub struct ValidatedJson<T>(pub T);
+
+// Requires the authenticated user to have the role `R`, e.g. `RequireRole<roles::Admin>`
+pub struct RequireRole<R: Role> {
+    pub user: AuthUser,
+    _role: PhantomData<R>,
+}
+
+// Requires the authenticated user to have the permission `P` through any of their roles,
+// e.g. `RequirePermission<permissions::UsersRead>`
+pub struct RequirePermission<P: Permission> {
+    pub user: AuthUser,
+    _permission: PhantomData<P>,
+}
+
+// Roles and permissions are names in the database, these traits tie marker types to them
+pub trait Role {
+    const NAME: &'static str;
+}
+
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+pub mod roles {
+    use super::Role;
+
+    pub struct Admin;
+
+    impl Role for Admin {
+        const NAME: &'static str = "admin";
+    }
+}
+
+pub mod permissions {
+    use super::Permission;
+
+    pub struct UsersRead;
+    pub struct UsersWrite;
+    pub struct RolesGrant;
+
+    impl Permission for UsersRead {
+        const NAME: &'static str = "users:read";
+    }
+
+    impl Permission for UsersWrite {
+        const NAME: &'static str = "users:write";
+    }
+
+    impl Permission for RolesGrant {
+        const NAME: &'static str = "roles:grant";
+    }
+}
+
+// A user that passed the password check but still has to provide the second factor
+pub struct MfaPendingUser {
+    pub user_id: Uuid,
+}
+
 pub struct CsrfValidator;
 
+// Information about the client, stored with new sessions
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// The most preferred locale of the Accept-Language header that has email templates
+pub struct AcceptLanguage(pub Option<String>);
+
 #[derive(serde::Serialize, serde::Deserialize)]
 struct AuthClaims {
     sub: Uuid,
+    sid: Uuid,
     exp: i64,
 }
 
-pub fn hash_password(password: String) -> Result<String, HTTPError> {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+// Deliberately disjoint from `AuthClaims`, neither token decodes as the other
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MfaClaims {
+    sub: Uuid,
+    mfa_pending: bool,
+    exp: i64,
+}
 
-    match argon2.hash_password(password.as_bytes(), &salt) {
-        Ok(password_hash) => Ok(password_hash.to_string()),
+pub async fn hash_password(password: String, state: &AppState) -> Result<String, HTTPError> {
+    match state.hasher.hash(password).await {
+        Ok(password_hash) => Ok(password_hash),
         Err(e) => {
             log::debug!("Failed to hash password: {:?}", e);
             Err(HTTPError::InternalServerError)
@@ -49,43 +145,305 @@ pub fn hash_password(password: String) -> Result<String, HTTPError> {
     }
 }
 
-pub fn validate_password(password: String, password_hash: &str) -> Result<bool, HTTPError> {
-    let parsed_hash = PasswordHash::new(password_hash).map_err(|e| {
-        log::debug!("Invalid password hash format: {:?}", e);
-        HTTPError::Unauthorized
-    })?;
-    let result = Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok();
+pub async fn check_password_policy(
+    password: &str,
+    username: &str,
+    email: &str,
+    state: &AppState,
+) -> Result<(), HTTPError> {
+    let violations = state.password_policy.check(password, username, email).await;
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(HTTPError::PasswordPolicy(violations)),
+    }
+}
+
+pub async fn validate_password(
+    password: String,
+    password_hash: &str,
+    state: &AppState,
+) -> Result<bool, HTTPError> {
+    check_password(password, password_hash, state).await?;
+    Ok(true)
+}
+
+// Like `validate_password`, but also tells whether the hash should be replaced. New passwords
+// are hashed normalized, older hashes may be of the password as typed, so both are tried.
+async fn check_password(
+    password: String,
+    password_hash: &str,
+    state: &AppState,
+) -> Result<bool, HTTPError> {
+    let mut normalized = password.clone();
+    validation::normalize(&mut normalized);
+    let raw = (normalized != password).then_some(password);
 
-    match result {
-        true => Ok(true),
-        false => Err(HTTPError::Unauthorized),
+    if let Verification::Match { outdated } =
+        verify_password(normalized, password_hash, state).await?
+    {
+        return Ok(outdated);
     }
+    // A hash of the password as typed is replaced, so the normalized one matches from now on
+    match raw {
+        Some(raw) => match verify_password(raw, password_hash, state).await? {
+            Verification::Match { .. } => Ok(true),
+            Verification::Mismatch => Err(HTTPError::Unauthorized),
+        },
+        None => Err(HTTPError::Unauthorized),
+    }
+}
+
+async fn verify_password(
+    password: String,
+    password_hash: &str,
+    state: &AppState,
+) -> Result<Verification, HTTPError> {
+    state
+        .hasher
+        .verify(password, password_hash.to_owned())
+        .await
+        .map_err(|e| {
+            log::debug!("Invalid password hash format: {:?}", e);
+            HTTPError::Unauthorized
+        })
 }
 
 pub async fn auth_user(
     username: &str,
     password: String,
+    state: &AppState,
+) -> Result<Uuid, HTTPError> {
+    let db = &state.db;
+    // Fetch password hash from the database, an unknown username is just a wrong login and
+    // takes as long to reject, so the response time does not tell which usernames exist
+    let (id, password_hash) = match crud::user::get_hash(username, db).await {
+        Ok(row) => row,
+        Err(sqlx::Error::RowNotFound) => {
+            let _ = check_password(password, state.hasher.dummy_hash(), state).await;
+            return Err(HTTPError::Unauthorized);
+        }
+        Err(e) => return Err(HTTPError::from(e)),
+    };
+
+    // Validate the password, the plaintext is kept in case the hash has to be replaced
+    let mut password = password;
+    let outdated = check_password(password.clone(), &password_hash, state).await?;
+    validation::normalize(&mut password);
+
+    // Only tell someone who knows the password that the account is suspended
+    if crud::user::is_suspended(&id, db).await? {
+        return Err(HTTPError::Suspended);
+    }
+
+    if !crud::user::is_verified(&id, db).await? {
+        return Err(HTTPError::EmailNotVerified);
+    }
+
+    // Upgrade legacy or weaker hashes while the password is at hand, in the background so the
+    // login does not wait for a second hash
+    if outdated {
+        let hasher = state.hasher.clone();
+        let db = db.clone();
+        state.tasks.spawn(async move {
+            let result = match hasher.hash(password).await {
+                Ok(new_hash) => {
+                    crud::user::replace_password_hash(&id, &password_hash, &new_hash, &db).await
+                }
+                Err(e) => Err(HTTPError::Anyhow(e.into())),
+            };
+            match result {
+                Ok(true) => log::info!("Rehashed password of user {}", id),
+                Ok(false) => log::debug!("Password of user {} changed before rehashing", id),
+                Err(e) => log::error!("Failed to rehash password of user {}: {:?}", id, e),
+            }
+        });
+    }
+
+    Ok(id)
+}
+
+// Time until the next login attempt is allowed, None if one is allowed right away
+fn login_retry_after(
+    throttle: &Throttle,
+    config: &Config,
+    now: OffsetDateTime,
+) -> Option<time::Duration> {
+    if let Some(locked_until) = throttle.locked_until
+        && locked_until > now
+    {
+        return Some(locked_until - now);
+    }
+    // Failures older than the lockout duration are forgotten, counting restarts
+    if now - throttle.last_failure >= config.login_lockout_duration() {
+        return None;
+    }
+    // Addresses are shared, one failure must not slow down everyone behind the same NAT.
+    // They are only locked once their much higher threshold is reached.
+    if throttle.scope == Scope::Ip || throttle.failures == 0 {
+        return None;
+    }
+
+    // base, 2 * base, 4 * base, ... up to the configured maximum
+    let exponent = (throttle.failures - 1).clamp(0, 30) as u32;
+    let delay = config
+        .login_backoff_base
+        .saturating_mul(1 << exponent)
+        .min(config.login_backoff_max);
+    let next_attempt = throttle.last_failure + time::Duration::seconds(delay);
+
+    (next_attempt > now).then(|| next_attempt - now)
+}
+
+// Reject a login attempt before any password is checked if the account or the client address
+// failed too often recently
+pub async fn check_login_throttle(
+    username: &str,
+    client: &ClientInfo,
+    state: &AppState,
+) -> Result<(), HTTPError> {
+    let throttles = crud::login_throttle::get_throttles(
+        &username.to_lowercase(),
+        client.ip.as_deref(),
+        &state.db,
+    )
+    .await?;
+
+    let now = OffsetDateTime::now_utc();
+    let retry_after = throttles
+        .iter()
+        .filter_map(|throttle| login_retry_after(throttle, &state.config, now))
+        .max();
+
+    match retry_after {
+        Some(retry_after) => Err(HTTPError::TooManyRequests {
+            // Rounded up, retrying after the announced time must not be rejected again
+            retry_after: retry_after.whole_seconds() as u64 + 1,
+        }),
+        None => Ok(()),
+    }
+}
+
+// Count a failed password or second factor, locking the account or the client address once
+// their threshold is reached
+pub async fn record_login_failure(
+    username: &str,
+    client: &ClientInfo,
+    state: &Arc<AppState>,
+) -> Result<(), HTTPError> {
+    let config = &state.config;
+    let now = OffsetDateTime::now_utc();
+    let forget_before = now - config.login_lockout_duration();
+    let locked_until = now + config.login_lockout_duration();
+
+    let account = username.to_lowercase();
+    let failures =
+        crud::login_throttle::record_failure(Scope::Account, &account, forget_before, &state.db)
+            .await?;
+    if failures >= config.login_lockout_threshold {
+        crud::login_throttle::lock(Scope::Account, &account, locked_until, &state.db).await?;
+        log::warn!(
+            "Account {} locked after {} failed logins",
+            account,
+            failures
+        );
+
+        // Unknown usernames are locked as well, but there is nobody to notify
+        if let Ok(user) = crud::user::get_user_by_username(username, &state.db).await {
+            let mut conn = state.db.acquire().await?;
+            utils::queue_account_locked(
+                &user.email,
+                user.locale.as_deref(),
+                config.login_lockout_duration(),
+                state,
+                &mut conn,
+            )
+            .await?;
+        }
+    }
+
+    if let Some(ip) = client.ip.as_deref() {
+        let failures =
+            crud::login_throttle::record_failure(Scope::Ip, ip, forget_before, &state.db).await?;
+        if failures >= config.login_ip_lockout_threshold {
+            crud::login_throttle::lock(Scope::Ip, ip, locked_until, &state.db).await?;
+            log::warn!("Address {} locked after {} failed logins", ip, failures);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn verify_second_factor(
+    user_id: &Uuid,
+    code: &str,
     db: &PgPool,
-) -> Result<AuthUser, HTTPError> {
-    // Fetch password hash from the database
-    let (id, password_hash) = crud::user::get_hash(username, db).await?;
+) -> Result<(), HTTPError> {
+    // Accept `123 456` or `abcde-fghij` the way users tend to type them
+    let code: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_lowercase();
+
+    let accepted =
+        if code.len() == totp::DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit()) {
+            let secret = match crud::two_factor::get_secret(user_id, db).await? {
+                Some((secret, true)) => secret,
+                _ => return Err(HTTPError::Unauthorized),
+            };
+            let secret = totp::decode_secret(&secret).ok_or_else(|| {
+                log::error!("Stored TOTP secret of user {} is not valid base32", user_id);
+                HTTPError::InternalServerError
+            })?;
+            let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
 
-    // Validate the password
-    validate_password(password, &password_hash)?;
+            match totp::verify(&secret, &code, now) {
+                Some(step) => crud::two_factor::use_step(user_id, step as i64, db).await?,
+                None => false,
+            }
+        } else {
+            crud::two_factor::consume_recovery_code(user_id, &utils::hash_token(&code), db).await?
+        };
 
-    Ok(AuthUser { user_id: id })
+    match accepted {
+        true => Ok(()),
+        false => {
+            log::debug!("Second factor rejected");
+            Err(HTTPError::Unauthorized)
+        }
+    }
 }
 
 impl AuthUser {
+    // Start a new server-side session, the session id is embedded in the JWT
+    pub(in crate::http) async fn create_session(
+        user_id: Uuid,
+        client: &ClientInfo,
+        db: &PgPool,
+    ) -> Result<Self, HTTPError> {
+        let session_id = crud::session::create_session(
+            &user_id,
+            client.user_agent.as_deref(),
+            client.ip.as_deref(),
+            db,
+        )
+        .await?;
+
+        Ok(AuthUser {
+            user_id,
+            session_id,
+        })
+    }
+
     pub(in crate::http) fn to_jwt(&self, context: &AppState) -> Result<String, HTTPError> {
         let secret = &context.config.hmac_key;
         let token = encode(
             &Header::default(),
             &AuthClaims {
                 sub: self.user_id,
-                exp: (OffsetDateTime::now_utc() + DEFAULT_SESSION_DURATION).unix_timestamp(),
+                sid: self.session_id,
+                exp: (OffsetDateTime::now_utc() + context.config.access_token_lifetime())
+                    .unix_timestamp(),
             },
             &EncodingKey::from_secret(secret.as_ref()),
         );
@@ -101,7 +459,21 @@ impl AuthUser {
             }
         }
     }
-    fn from_authorization(ctx: &AppState, jwt_token: &str) -> Result<Self, HTTPError> {
+
+    // The user a valid access token was issued to, without looking at the session. Good enough
+    // to tell clients apart, never to authorize anything.
+    pub(in crate::http) fn claimed_user_id(ctx: &AppState, jwt_token: &str) -> Option<Uuid> {
+        let secret = &ctx.config.hmac_key;
+        decode::<AuthClaims>(
+            jwt_token,
+            &DecodingKey::from_secret(secret.as_ref()),
+            &Validation::default(),
+        )
+        .ok()
+        .map(|token| token.claims.sub)
+    }
+
+    async fn from_authorization(ctx: &AppState, jwt_token: &str) -> Result<Self, HTTPError> {
         let secret = &ctx.config.hmac_key;
         // `token` is a struct with 2 fields: `header` and `claims` where `claims` is your own struct.
         let token = decode::<AuthClaims>(
@@ -114,12 +486,48 @@ impl AuthUser {
             HTTPError::Unauthorized
         });
 
-        match token {
-            Ok(token) => Ok(AuthUser {
-                user_id: token.claims.sub,
-            }),
-            Err(e) => Err(e),
+        let claims = token?.claims;
+
+        // A valid signature is not enough, the session must not have been revoked
+        let (last_seen, suspended) =
+            crud::session::get_last_seen(&claims.sid, &claims.sub, &ctx.db)
+                .await?
+                .ok_or_else(|| {
+                    log::debug!("Session is revoked or does not exist");
+                    HTTPError::Unauthorized
+                })?;
+
+        if suspended {
+            return Err(HTTPError::Suspended);
+        }
+
+        if OffsetDateTime::now_utc() - last_seen > SESSION_TOUCH_INTERVAL {
+            crud::session::touch_session(&claims.sid, &ctx.db).await?;
         }
+
+        Ok(AuthUser {
+            user_id: claims.sub,
+            session_id: claims.sid,
+        })
+    }
+}
+
+impl MfaPendingUser {
+    pub(in crate::http) fn to_jwt(user_id: Uuid, context: &AppState) -> Result<String, HTTPError> {
+        let secret = &context.config.hmac_key;
+        encode(
+            &Header::default(),
+            &MfaClaims {
+                sub: user_id,
+                mfa_pending: true,
+                exp: (OffsetDateTime::now_utc() + MFA_PENDING_DURATION).unix_timestamp(),
+            },
+            &EncodingKey::from_secret(secret.as_ref()),
+        )
+        .map_err(|e| {
+            log::debug!("Failed to encode mfa token: {:?}", e);
+            HTTPError::InternalServerError
+        })
     }
 }
 
@@ -179,13 +587,95 @@ where
                     log::debug!("JWT cookie is missing");
                     HTTPError::Unauthorized
                 })?;
-                AuthUser::from_authorization(ctx, cookie.value())
+                AuthUser::from_authorization(ctx, cookie.value()).await
             }
             Err(_) => Err(HTTPError::Unauthorized),
         }
     }
 }
 
+impl<S, R> FromRequestParts<S> for RequireRole<R>
+where
+    S: Send + Sync + AsRef<AppState>,
+    R: Role,
+{
+    type Rejection = HTTPError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+
+        match crud::role::has_role(&user.user_id, R::NAME, &state.as_ref().db).await? {
+            true => Ok(Self {
+                user,
+                _role: PhantomData,
+            }),
+            false => {
+                log::debug!("User {} lacks role {}", user.user_id, R::NAME);
+                Err(HTTPError::Forbidden)
+            }
+        }
+    }
+}
+
+impl<S, P> FromRequestParts<S> for RequirePermission<P>
+where
+    S: Send + Sync + AsRef<AppState>,
+    P: Permission,
+{
+    type Rejection = HTTPError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+
+        match crud::role::has_permission(&user.user_id, P::NAME, &state.as_ref().db).await? {
+            true => Ok(Self {
+                user,
+                _permission: PhantomData,
+            }),
+            false => {
+                log::debug!("User {} lacks permission {}", user.user_id, P::NAME);
+                Err(HTTPError::Forbidden)
+            }
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for MfaPendingUser
+where
+    S: Send + Sync + AsRef<AppState>,
+{
+    type Rejection = HTTPError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ctx = state.as_ref();
+
+        let jar = CookieJar::from_request_parts(parts, state)
+            .await
+            .map_err(|_| HTTPError::Unauthorized)?;
+        let cookie = jar.get(DEFAULT_MFA).ok_or_else(|| {
+            log::debug!("MFA cookie is missing");
+            HTTPError::Unauthorized
+        })?;
+
+        let token = decode::<MfaClaims>(
+            cookie.value(),
+            &DecodingKey::from_secret(ctx.config.hmac_key.as_ref()),
+            &Validation::default(),
+        )
+        .map_err(|e| {
+            log::debug!("Failed to decode mfa token: {:?}", e);
+            HTTPError::Unauthorized
+        })?;
+
+        match token.claims.mfa_pending {
+            true => Ok(Self {
+                user_id: token.claims.sub,
+            }),
+            false => Err(HTTPError::Unauthorized),
+        }
+    }
+}
+
 impl<S> FromRequestParts<S> for OptionalAuthUser
 where
     S: Send + Sync + AsRef<AppState>,
@@ -198,18 +688,185 @@ where
 
         let jar = CookieJar::from_request_parts(parts, state).await;
 
-        match jar {
-            Ok(jar) => {
-                // Try to get the cookie for JWT authorization
-                if let Some(cookie) = jar.get(DEFAULT_AUTH) {
-                    if let Ok(auth_user) = AuthUser::from_authorization(ctx, cookie.value()) {
-                        return Ok(Self(Some(auth_user)));
-                    }
-                }
-            }
-            Err(_) => {}
+        // Try to get the cookie for JWT authorization
+        if let Ok(jar) = jar
+            && let Some(cookie) = jar.get(DEFAULT_AUTH)
+            && let Ok(auth_user) = AuthUser::from_authorization(ctx, cookie.value()).await
+        {
+            return Ok(Self(Some(auth_user)));
         }
 
         Ok(Self(None))
     }
 }
+
+impl FromRequestParts<Arc<AppState>> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let ip =
+            forwarded::client_ip(parts, &state.config.trusted_proxies).map(|ip| ip.to_string());
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned);
+
+        Ok(Self { ip, user_agent })
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for AcceptLanguage {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+
+        // `de-AT, de;q=0.9, en;q=0.5`, a missing weight is 1 and 0 means not acceptable
+        let mut ranges: Vec<(&str, f32)> = header
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let tag = params.next()?.trim();
+                let weight = params
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                (!tag.is_empty() && tag != "*" && weight > 0.0).then_some((tag, weight))
+            })
+            .collect();
+        // Stable, ranges of equal weight keep their order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let locale = ranges
+            .into_iter()
+            .find_map(|(tag, _)| state.templates.negotiate(tag))
+            .map(str::to_owned);
+
+        Ok(Self(locale))
+    }
+}
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = HTTPError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(mut value) = Json::<T>::from_request(req, state).await?;
+        value.validate().map_err(HTTPError::invalid_fields)?;
+        Ok(ValidatedJson(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config() -> Config {
+        Config::parse_from([
+            "rust_backend",
+            "--database-url=postgres://localhost/test",
+            "--hmac-key=secret",
+            "--mail-sender=Example",
+            "--mail-from=noreply@example.com",
+            "--login-backoff-base=1",
+            "--login-backoff-max=60",
+            "--login-lockout-duration=900",
+        ])
+    }
+
+    fn throttle(scope: Scope, failures: i32, last_failure: OffsetDateTime) -> Throttle {
+        Throttle {
+            scope,
+            failures,
+            last_failure,
+            locked_until: None,
+        }
+    }
+
+    fn seconds(delay: Option<time::Duration>) -> Option<i64> {
+        delay.map(|delay| delay.whole_seconds())
+    }
+
+    #[test]
+    fn no_delay_without_failures_or_below_the_address_threshold() {
+        let config = config();
+        let now = OffsetDateTime::now_utc();
+
+        let account = throttle(Scope::Account, 0, now);
+        assert_eq!(login_retry_after(&account, &config, now), None);
+        // Addresses are not slowed down before they are locked
+        let address = throttle(Scope::Ip, 99, now);
+        assert_eq!(login_retry_after(&address, &config, now), None);
+    }
+
+    #[test]
+    fn delay_doubles_with_every_failure_up_to_the_cap() {
+        let config = config();
+        let now = OffsetDateTime::now_utc();
+
+        let delays: Vec<Option<i64>> = (1..=9)
+            .map(|failures| {
+                seconds(login_retry_after(
+                    &throttle(Scope::Account, failures, now),
+                    &config,
+                    now,
+                ))
+            })
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60, 60].map(Some).to_vec());
+        // Huge counts do not overflow the shift
+        let many = throttle(Scope::Account, i32::MAX, now);
+        assert_eq!(seconds(login_retry_after(&many, &config, now)), Some(60));
+    }
+
+    #[test]
+    fn delay_counts_from_the_last_failure() {
+        let config = config();
+        let now = OffsetDateTime::now_utc();
+
+        let recent = throttle(Scope::Account, 4, now - time::Duration::seconds(3));
+        assert_eq!(seconds(login_retry_after(&recent, &config, now)), Some(5));
+        let waited = throttle(Scope::Account, 4, now - time::Duration::seconds(8));
+        assert_eq!(login_retry_after(&waited, &config, now), None);
+    }
+
+    #[test]
+    fn lock_applies_until_it_ends() {
+        let config = config();
+        let now = OffsetDateTime::now_utc();
+
+        let mut locked = throttle(Scope::Ip, 100, now - time::Duration::seconds(60));
+        locked.locked_until = Some(now + time::Duration::seconds(840));
+        assert_eq!(seconds(login_retry_after(&locked, &config, now)), Some(840));
+
+        locked.locked_until = Some(now - time::Duration::seconds(1));
+        assert_eq!(login_retry_after(&locked, &config, now), None);
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let mut config = config();
+        // A cap beyond the window shows that the window wins
+        config.login_backoff_max = 3600;
+        let now = OffsetDateTime::now_utc();
+
+        let inside = throttle(Scope::Account, 20, now - time::Duration::seconds(899));
+        assert!(login_retry_after(&inside, &config, now).is_some());
+        let expired = throttle(Scope::Account, 20, now - time::Duration::seconds(900));
+        assert_eq!(login_retry_after(&expired, &config, now), None);
+    }
+}
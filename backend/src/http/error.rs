@@ -1,8 +1,16 @@
-use axum::http::header::WWW_AUTHENTICATE;
-use axum::http::StatusCode;
+use axum::extract::rejection::JsonRejection;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use crate::http::problem::Problem;
+use crate::password_policy::Violation;
+use crate::schemas::validation::{Invalid, ValidationErrors};
 use sqlx::error::DatabaseError;
 
+// Authentication is a cookie set by the login endpoint, there is no registered scheme for that
+const WWW_AUTHENTICATE_COOKIE: &str =
+    r#"Cookie realm="api", form-action="/token/get", cookie-name="jwt""#;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("authentication required")]
@@ -25,9 +33,47 @@ pub enum Error {
 
     #[error("conflict, resource already exists")]
     Conflict,
+
+    #[error("{field} is already taken")]
+    Taken { field: &'static str },
+
+    #[error("account is suspended")]
+    Suspended,
+
+    #[error("email address is not verified")]
+    EmailNotVerified,
+
+    #[error("too many requests, retry later")]
+    TooManyRequests { retry_after: u64 },
+
+    #[error("password does not meet the requirements")]
+    PasswordPolicy(Vec<Violation>),
+
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{detail}")]
+    UnprocessableEntity {
+        detail: String,
+        errors: ValidationErrors,
+    },
+
+    #[error("request body is too large")]
+    PayloadTooLarge,
+
+    #[error("{0}")]
+    UnsupportedMediaType(String),
 }
 
 impl Error {
+    /// A request body with invalid fields
+    pub fn invalid_fields(errors: ValidationErrors) -> Self {
+        Self::UnprocessableEntity {
+            detail: "request body is invalid".to_owned(),
+            errors,
+        }
+    }
+
     fn status_code(&self) -> StatusCode {
         match self {
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
@@ -36,6 +82,54 @@ impl Error {
             Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Sqlx(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Conflict => StatusCode::CONFLICT,
+            Self::Taken { .. } => StatusCode::CONFLICT,
+            Self::Suspended => StatusCode::FORBIDDEN,
+            Self::EmailNotVerified => StatusCode::FORBIDDEN,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::PasswordPolicy(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+    }
+
+    /// Stable name of the error for clients, and a title that does not change between
+    /// occurrences
+    fn code(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::Unauthorized => ("unauthorized", "Authentication required"),
+            Self::Forbidden => ("forbidden", "Forbidden"),
+            Self::NotFound => ("not_found", "Not found"),
+            Self::InternalServerError | Self::Sqlx(_) | Self::Anyhow(_) => {
+                ("internal_error", "Internal server error")
+            }
+            Self::Conflict => ("conflict", "Conflict"),
+            Self::Taken { .. } => ("already_taken", "Already taken"),
+            Self::Suspended => ("account_suspended", "Account suspended"),
+            Self::EmailNotVerified => ("email_not_verified", "Email address not verified"),
+            Self::TooManyRequests { .. } => ("too_many_requests", "Too many requests"),
+            Self::PasswordPolicy(_) => ("password_policy", "Password rejected"),
+            Self::BadRequest(_) => ("bad_request", "Bad request"),
+            Self::UnprocessableEntity { .. } => ("unprocessable_entity", "Unprocessable entity"),
+            Self::PayloadTooLarge => ("payload_too_large", "Payload too large"),
+            Self::UnsupportedMediaType(_) => ("unsupported_media_type", "Unsupported media type"),
+        }
+    }
+}
+
+// Rejections of the JSON extractor, keeping the status axum chose for them
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Self {
+        let detail = rejection.body_text();
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::UnsupportedMediaType(detail),
+            StatusCode::UNPROCESSABLE_ENTITY => Self::UnprocessableEntity {
+                detail,
+                errors: ValidationErrors::default(),
+            },
+            _ => Self::BadRequest(detail),
         }
     }
 }
@@ -43,15 +137,6 @@ impl Error {
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         match self {
-            Self::Unauthorized => {
-                return (
-                    self.status_code(),
-                    [(WWW_AUTHENTICATE, "JWT")],
-                    self.to_string(),
-                )
-                    .into_response();
-            }
-
             Self::Sqlx(ref e) => {
                 log::error!("SQLx error: {:?}", e);
             }
@@ -63,7 +148,33 @@ impl IntoResponse for Error {
             _ => (),
         }
 
-        (self.status_code(), self.to_string()).into_response()
+        let header = match self {
+            Self::Unauthorized => Some((
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static(WWW_AUTHENTICATE_COOKIE),
+            )),
+            Self::TooManyRequests { retry_after } => Some((RETRY_AFTER, retry_after.into())),
+            _ => None,
+        };
+
+        let (code, title) = self.code();
+        let mut problem = Problem::new(self.status_code(), code, title, self.to_string());
+        match self {
+            Self::UnprocessableEntity { errors, .. } => problem.errors = errors,
+            // Points at the field to change, like a validation error
+            Self::Taken { field } => problem
+                .errors
+                .check(field, Err(Invalid::new("taken", "is already taken"))),
+            // Every violated rule is listed so the client can show them all at once
+            Self::PasswordPolicy(violations) => problem.violations = violations,
+            _ => (),
+        }
+
+        let mut response = problem.into_response();
+        if let Some((name, value)) = header {
+            response.headers_mut().insert(name, value);
+        }
+        response
     }
 }
 
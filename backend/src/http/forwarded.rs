@@ -0,0 +1,180 @@
+// The address of the client behind reverse proxies
+//
+// Proxies name the client in `Forwarded` (RFC 7239) or `X-Forwarded-For`, each appending the
+// address it received the request from. Anyone can send these headers, so they are only read
+// from a peer in `trusted_proxies` or on the Unix socket, and the list is walked from the right
+// until the first address that is not a trusted proxy.
+use crate::http::listener::PeerAddr;
+use axum::extract::ConnectInfo;
+use http::{HeaderMap, header::FORWARDED, request::Parts};
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The address of the client, `None` when neither the peer nor a trusted proxy tells it
+pub fn client_ip(parts: &Parts, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = peer(parts)?;
+    let trusted = match peer.ip() {
+        Some(ip) => trusted_proxies.contains(&ip),
+        None => true,
+    };
+    if !trusted {
+        return peer.ip();
+    }
+
+    match forwarded_for(&parts.headers) {
+        Some(hops) => client_of(&hops, trusted_proxies),
+        None => peer.ip(),
+    }
+}
+
+/// A rate limit key for the client, never shared by unrelated clients. Without an address each
+/// Unix socket connection gets its own.
+pub fn client_key(parts: &Parts, trusted_proxies: &[IpAddr]) -> String {
+    match (client_ip(parts, trusted_proxies), peer(parts)) {
+        (Some(ip), _) => format!("ip:{}", ip),
+        (None, Some(PeerAddr::Unix(connection))) => format!("conn:{}", connection),
+        (None, Some(PeerAddr::Tcp(addr))) => format!("ip:{}", addr.ip()),
+        // Only requests that did not come through the listener, i.e. none in production
+        (None, None) => "conn:unknown".to_owned(),
+    }
+}
+
+fn peer(parts: &Parts) -> Option<PeerAddr> {
+    parts
+        .extensions
+        .get::<ConnectInfo<PeerAddr>>()
+        .map(|ConnectInfo(addr)| *addr)
+}
+
+// The first untrusted hop from the right, the leftmost when every hop is a trusted proxy. An
+// obfuscated or unparsable hop ends the walk, nothing left of it can be believed.
+fn client_of(hops: &[Option<IpAddr>], trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    for hop in hops.iter().rev() {
+        match hop {
+            Some(ip) if trusted_proxies.contains(ip) => continue,
+            _ => return *hop,
+        }
+    }
+    hops.first().copied().flatten()
+}
+
+// The hops of `Forwarded`, or of `X-Forwarded-For` when there is none, in the order the proxies
+// added them
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let forwarded: Vec<Option<IpAddr>> = headers
+        .get_all(FORWARDED)
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim().trim_matches('"')))
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return Some(forwarded);
+    }
+
+    let x_forwarded_for: Vec<Option<IpAddr>> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .map(|node| parse_node(node.trim()))
+        .collect();
+    match x_forwarded_for.is_empty() {
+        true => None,
+        false => Some(x_forwarded_for),
+    }
+}
+
+// `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::17` or `[2001:db8::17]:4711`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        let (ip, port) = node.rsplit_once(':')?;
+        port.parse::<u16>().ok()?;
+        ip.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use http::Request;
+    use std::net::SocketAddr;
+
+    fn parts(peer: PeerAddr, headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::builder();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request.into_parts().0
+    }
+
+    fn tcp(ip: &str) -> PeerAddr {
+        PeerAddr::Tcp(SocketAddr::new(ip.parse().unwrap(), 50000))
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn headers_of_untrusted_peers_are_ignored() {
+        let parts = parts(tcp("198.51.100.7"), &[("x-forwarded-for", "203.0.113.1")]);
+        assert_eq!(client_ip(&parts, &[]), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn trusted_proxy_names_the_client() {
+        let proxy = "10.0.0.2".parse().unwrap();
+        let parts = parts(
+            tcp("10.0.0.2"),
+            &[("x-forwarded-for", "203.0.113.9, 203.0.113.1, 10.0.0.2")],
+        );
+        // The leftmost hops were sent by the client itself
+        assert_eq!(client_ip(&parts, &[proxy]), ip("203.0.113.1"));
+    }
+
+    #[test]
+    fn unix_socket_peer_is_trusted() {
+        let parts = parts(
+            PeerAddr::Unix(1),
+            &[(
+                "forwarded",
+                "for=192.0.2.43, for=\"[2001:db8:cafe::17]:4711\";proto=https",
+            )],
+        );
+        assert_eq!(client_ip(&parts, &[]), ip("2001:db8:cafe::17"));
+    }
+
+    #[test]
+    fn forwarded_node_forms() {
+        assert_eq!(parse_node("192.0.2.60"), ip("192.0.2.60"));
+        assert_eq!(parse_node("192.0.2.60:4711"), ip("192.0.2.60"));
+        assert_eq!(parse_node("2001:db8::17"), ip("2001:db8::17"));
+        assert_eq!(parse_node("[2001:db8::17]:4711"), ip("2001:db8::17"));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn key_is_never_empty() {
+        let first = parts(PeerAddr::Unix(1), &[]);
+        let second = parts(PeerAddr::Unix(2), &[]);
+        assert_eq!(client_key(&first, &[]), "conn:1");
+        assert_eq!(client_key(&second, &[]), "conn:2");
+
+        let unknown = parts(PeerAddr::Unix(3), &[("forwarded", "for=unknown")]);
+        assert_eq!(client_key(&unknown, &[]), "conn:3");
+    }
+}
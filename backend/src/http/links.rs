@@ -0,0 +1,69 @@
+// Every URL the API hands out, in emails and redirects
+//
+// Links to API routes start with `public_base_url`, links to pages with `frontend_url`. Both
+// may have a path, e.g. when the API is served under `/api`. Tokens are percent-encoded as
+// path segments.
+use crate::config::Config;
+use url::Url;
+
+#[derive(Clone)]
+pub struct Links {
+    api: Url,
+    frontend: Url,
+}
+
+impl Links {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            api: config.public_base_url.clone(),
+            frontend: config.frontend_url.clone(),
+        }
+    }
+
+    /// Opened from the verification email, verifies the address
+    pub fn verify_email(&self, token: &str) -> String {
+        build(&self.api, &["users", "verify", token], &[])
+    }
+
+    /// The page confirming an email change, opened from the email to the new address
+    pub fn confirm_email_change(&self, token: &str) -> String {
+        build(&self.frontend, &["confirm-email", token], &[])
+    }
+
+    /// The page cancelling an email change, opened from the email to the old address
+    pub fn cancel_email_change(&self, token: &str) -> String {
+        build(&self.frontend, &["cancel-email-change", token], &[])
+    }
+
+    /// The page where a new password is chosen
+    pub fn reset_password(&self, token: &str) -> String {
+        build(&self.frontend, &["reset-password", token], &[])
+    }
+
+    pub fn login(&self) -> String {
+        build(&self.frontend, &["login"], &[])
+    }
+
+    /// The login page telling the outcome of an email link, e.g. `/login?verified=true`
+    pub fn login_with(&self, outcome: &str, success: bool) -> String {
+        let success = if success { "true" } else { "false" };
+        build(&self.frontend, &["login"], &[(outcome, success)])
+    }
+
+    pub fn register(&self) -> String {
+        build(&self.frontend, &["register"], &[])
+    }
+}
+
+fn build(base: &Url, segments: &[&str], query: &[(&str, &str)]) -> String {
+    let mut url = base.clone();
+    // Validated at startup, URLs with a host always have a path
+    if let Ok(mut path) = url.path_segments_mut() {
+        path.pop_if_empty().extend(segments);
+    }
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+
+    url.into()
+}
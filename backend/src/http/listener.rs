@@ -0,0 +1,191 @@
+// Where the server accepts connections
+//
+// TCP on `bind_address` and `port`, HTTPS when a certificate is configured, or a Unix socket
+// for a proxy on the same host. When systemd starts the service with socket activation, the
+// socket it passes is used instead, TCP or Unix.
+use crate::config::Config;
+use crate::http::tls::{Certificate, TlsListener};
+use anyhow::Context;
+use axum::{Router, extract::connect_info::Connected, serve::IncomingStream};
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::fs::FileTypeExt,
+    },
+    path::Path,
+    sync::Arc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::net::{TcpListener, UnixListener};
+
+// Sockets passed by systemd start at this descriptor
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Numbers the connections of Unix socket peers, which have no address to tell them apart
+static UNIX_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    Unix(UnixListener),
+}
+
+/// The peer of a connection, available to handlers as `ConnectInfo<PeerAddr>`
+#[derive(Clone, Copy, Debug)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// The address of a Unix socket peer says nothing about the client, only the number of the
+    /// connection is known
+    Unix(u64),
+}
+
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self::Tcp(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        Self::Tcp(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Self::Unix(UNIX_CONNECTIONS.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Listener {
+    /// Bind the configured socket, or take over the one passed by systemd
+    pub async fn bind(
+        config: &Config,
+        certificate: Option<Arc<Certificate>>,
+    ) -> anyhow::Result<Self> {
+        let tcp = match activated_socket()? {
+            Some(fd) => {
+                let tcp = std::net::TcpListener::from(fd);
+                match tcp.local_addr() {
+                    Ok(_) => {
+                        tcp.set_nonblocking(true)?;
+                        TcpListener::from_std(tcp)?
+                    }
+                    // Not an IP socket
+                    Err(_) => {
+                        let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+                        unix.set_nonblocking(true)?;
+                        if certificate.is_some() {
+                            anyhow::bail!("TLS is not supported on a Unix socket");
+                        }
+                        log::info!("Listening on the Unix socket passed by systemd");
+                        return Ok(Self::Unix(UnixListener::from_std(unix)?));
+                    }
+                }
+            }
+            None => match &config.unix_socket {
+                Some(path) => {
+                    remove_stale_socket(path)?;
+                    let unix = UnixListener::bind(path)
+                        .with_context(|| format!("could not listen on {}", path.display()))?;
+                    log::info!("Listening on {}", path.display());
+                    return Ok(Self::Unix(unix));
+                }
+                None => {
+                    let addr = SocketAddr::new(config.bind_address, config.port);
+                    TcpListener::bind(addr)
+                        .await
+                        .with_context(|| format!("could not listen on {}", addr))?
+                }
+            },
+        };
+
+        let addr = tcp.local_addr()?;
+        Ok(match certificate {
+            Some(certificate) => {
+                log::info!("Listening on https://{}", addr);
+                Self::Tls(TlsListener::new(tcp, certificate))
+            }
+            None => {
+                log::info!("Listening on http://{}", addr);
+                Self::Tcp(tcp)
+            }
+        })
+    }
+
+    /// Serve the app until `shutdown` resolves, then stop accepting connections and finish
+    /// once the open ones are done
+    pub async fn serve(
+        self,
+        app: Router,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> io::Result<()> {
+        // Connect info is needed to record the client address of sessions
+        let app = app.into_make_service_with_connect_info::<PeerAddr>();
+        match self {
+            Self::Tcp(listener) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            Self::Tls(listener) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            Self::Unix(listener) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+        }
+    }
+}
+
+// The first socket systemd passed to this process, see sd_listen_fds(3)
+fn activated_socket() -> anyhow::Result<Option<OwnedFd>> {
+    let Ok(pid) = std::env::var("LISTEN_PID") else {
+        return Ok(None);
+    };
+    // Inherited from a parent that was activated itself
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds: RawFd = std::env::var("LISTEN_FDS")
+        .context("LISTEN_PID is set without LISTEN_FDS")?
+        .parse()
+        .context("invalid LISTEN_FDS")?;
+    if fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        log::warn!("systemd passed {} sockets, only the first one is used", fds);
+    }
+
+    // SAFETY: systemd passes open sockets from SD_LISTEN_FDS_START on, this is the only place
+    // that takes ownership of them and it runs once at startup
+    Ok(Some(unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) }))
+}
+
+// A socket file left behind by a previous run would make binding fail
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("could not remove the stale socket {}", path.display())),
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -1,56 +1,149 @@
-use crate::SmtpManager;
 use crate::config::Config;
+use crate::crud;
+use crate::mailer::Mailer;
+use crate::password::Hasher;
+use crate::password_policy::{BreachChecker, PasswordPolicy, PwnedPasswords};
+use crate::templates::Templates;
 use anyhow::Context;
-use axum::Router;
 use axum::http::header::HeaderValue;
-use deadpool::managed::Pool;
+use axum::{Router, middleware};
 use http::{Method, header};
+use links::Links;
+use listener::Listener;
+use rate_limit::{KeyBy, RateLimit};
 use sqlx::PgPool;
 use std::sync::Arc;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tower_http::cors::CorsLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 
 pub mod error;
+pub mod forwarded;
+pub mod links;
+pub mod listener;
+pub mod outbox;
+pub mod problem;
+pub mod rate_limit;
+pub mod shutdown;
+pub mod tls;
 pub mod utils;
 
 mod dependencies;
 mod routers;
 
-async fn clean_db(db: PgPool) {
-    // Clean the database every 12 hours
+async fn clean_db(db: PgPool, config: Arc<Config>, shutdown: CancellationToken) {
+    // Clean the database every 12 hours, a started run is finished on shutdown
     loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(3600 * 12)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(3600 * 12)) => (),
+            _ = shutdown.cancelled() => return,
+        }
 
-        let result = sqlx::query!(
-            "DELETE FROM users WHERE is_verified = false AND created_at < NOW() - INTERVAL '1 day'"
-        )
-        .execute(&db)
-        .await;
+        // Expired and used email tokens, and users whose verification token expired
+        match crud::email_token::sweep(&db).await {
+            Ok(deleted) => log::info!("Database cleaned, {} unverified users deleted", deleted),
+            Err(e) => log::error!("Error cleaning the database: {:?}", e),
+        }
+
+        // Failed logins that are neither counted nor locking anything anymore
+        let forget_before = time::OffsetDateTime::now_utc() - config.login_lockout_duration();
+        if let Err(e) = crud::login_throttle::sweep(forget_before, &db).await {
+            log::error!("Error cleaning the database: {:?}", e);
+        }
 
-        if let Err(e) = result {
+        // Rate limit buckets refill within their period, quotas are not expected to span days
+        let updated_before = time::OffsetDateTime::now_utc() - time::Duration::days(1);
+        if let Err(e) = crud::rate_limit::sweep(updated_before, &db).await {
             log::error!("Error cleaning the database: {:?}", e);
         }
+
+        // Dead emails an admin had a month to requeue
+        let dead_before = time::OffsetDateTime::now_utc() - time::Duration::days(30);
+        match crud::email_outbox::sweep(dead_before, &db).await {
+            Ok(deleted) if deleted > 0 => log::warn!("Deleted {} dead emails", deleted),
+            Ok(_) => (),
+            Err(e) => log::error!("Error cleaning the database: {:?}", e),
+        }
     }
 }
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
     pub db: PgPool,
-    pub smtp_pool: Arc<Pool<SmtpManager>>,
+    pub mailer: Arc<dyn Mailer>,
+    pub rate_limits: Arc<rate_limit::Store>,
+    pub hasher: Arc<Hasher>,
+    pub password_policy: Arc<PasswordPolicy>,
+    pub templates: Arc<Templates>,
+    pub links: Links,
+    /// Cancelled when the server shuts down, background work stops at the next opportunity
+    pub shutdown: CancellationToken,
+    /// Background work the shutdown waits for, spawn detached tasks here
+    pub tasks: TaskTracker,
 }
 
-pub async fn serve(config: Config, db: PgPool, smtp_pool: Pool<SmtpManager>) -> anyhow::Result<()> {
+pub async fn serve(config: Config, db: PgPool, mailer: Arc<dyn Mailer>) -> anyhow::Result<()> {
     // Create shared state
+    let concurrency = config.password_hash_concurrency.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    let hasher = Hasher::new(
+        config.argon2_memory_kib,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+        concurrency,
+    )
+    .context("Invalid Argon2 parameters")?;
+
+    let breach_checker = match config.password_breach_check {
+        true => Some(
+            Arc::new(PwnedPasswords::new(config.pwned_passwords_url.clone())?)
+                as Arc<dyn BreachChecker>,
+        ),
+        false => None,
+    };
+    let password_policy = PasswordPolicy::new(
+        config.password_min_length,
+        config.password_max_length,
+        config.password_required_classes.clone(),
+        config.common_passwords_file.as_deref(),
+        breach_checker,
+    )
+    .context("Failed to read the common passwords file")?;
+    let templates = Templates::new(config.email_templates_dir.as_deref(), &config.mail_sender)
+        .context("Failed to load the email templates")?;
+    let certificate = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(
+            tls::Certificate::load(cert.clone(), key.clone())
+                .context("Failed to load the TLS certificate")?,
+        )),
+        _ => None,
+    };
+
     let shared_state = Arc::new(AppState {
+        hasher: Arc::new(hasher),
+        password_policy: Arc::new(password_policy),
+        templates: Arc::new(templates),
+        links: Links::new(&config),
+        rate_limits: Arc::new(rate_limit::Store::new(config.rate_limit_backend, &db)),
         config: Arc::new(config),
         db,
-        smtp_pool: Arc::new(smtp_pool),
+        mailer,
+        shutdown: CancellationToken::new(),
+        tasks: TaskTracker::new(),
     });
 
-    let origin = "http://localhost:3000".parse::<HeaderValue>().unwrap();
+    let origins = shared_state
+        .config
+        .cors_origins()
+        .iter()
+        .map(|origin| origin.parse::<HeaderValue>())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid CORS origin")?;
 
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PUT])
-        .allow_origin(origin)
+        .allow_origin(origins)
         .allow_credentials(true)
         .allow_headers([
             header::CONTENT_TYPE,
@@ -64,25 +157,93 @@ pub async fn serve(config: Config, db: PgPool, smtp_pool: Pool<SmtpManager>) ->
             header::HeaderName::from_static("x_csft"),
             header::HeaderName::from_static("s_csft"),
             header::HeaderName::from_static("jwt"),
-        ]);
+            header::HeaderName::from_static("x-request-id"),
+        ])
+        .expose_headers([header::HeaderName::from_static("x-request-id")]);
 
     // Build the app router
-    let app = create_router(&shared_state).layer(cors);
+    let requests = TaskTracker::new();
+    let app = create_router(&shared_state)
+        .layer(cors)
+        .layer(middleware::from_fn_with_state(
+            requests.clone(),
+            shutdown::track_request,
+        )); // Count in-flight requests
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    let listener = Listener::bind(&shared_state.config, certificate.clone()).await?;
+
+    // Make sure the configured user can administrate the instance
+    if let Some(username) = &shared_state.config.initial_admin {
+        let result = crud::role::grant_role_by_username(
+            username,
+            <dependencies::roles::Admin as dependencies::Role>::NAME,
+            &shared_state.db,
+        )
+        .await;
+        match result {
+            Ok(_) => log::info!("Granted admin role to {}", username),
+            Err(e) => log::warn!("Could not grant admin role to {}: {:?}", username, e),
+        }
+    }
+
+    // Start delivering queued emails
+    shared_state.tasks.spawn(outbox::run(shared_state.clone()));
 
     // Start the database cleaner
-    tokio::spawn(clean_db(shared_state.db.clone()));
+    shared_state.tasks.spawn(clean_db(
+        shared_state.db.clone(),
+        shared_state.config.clone(),
+        shared_state.shutdown.clone(),
+    ));
+
+    // Pick up rotated certificates
+    if let Some(certificate) = certificate {
+        let interval = tokio::time::Duration::from_secs(shared_state.config.tls_reload_interval);
+        shared_state
+            .tasks
+            .spawn(certificate.watch(interval, shared_state.shutdown.clone()));
+    }
+
+    // Stop on SIGTERM or SIGINT
+    tokio::spawn(shutdown::cancel_on_signal(shared_state.shutdown.clone()));
 
     // Start the server using the listener
-    axum::serve(listener, app)
-        .await
-        .context("Error running the server")
+    // The readiness probe fails during the delay, so load balancers stop sending traffic
+    // before the listener closes
+    let shutdown = shared_state.shutdown.clone();
+    let delay = tokio::time::Duration::from_secs(shared_state.config.shutdown_delay);
+    let server = listener.serve(app, async move {
+        shutdown.cancelled().await;
+        tokio::time::sleep(delay).await;
+    });
+    shutdown::drain(server, requests, &shared_state).await
 }
 
 // Create Router
 fn create_router(shared_state: &Arc<AppState>) -> Router {
-    Router::new()
+    let default_limit = RateLimit::new(
+        "default",
+        shared_state.config.rate_limit_default,
+        KeyBy::User,
+        shared_state,
+    );
+
+    let api = Router::new()
         .merge(routers::auth::router(shared_state.clone())) // Add auth router
         .merge(routers::user::router(shared_state.clone())) // Add user router
+        .merge(routers::two_factor::router(shared_state.clone())) // Add 2FA router
+        .merge(routers::roles::router(shared_state.clone())) // Add roles router
+        .merge(routers::admin::router(shared_state.clone())) // Add admin router
+        .layer(middleware::from_fn_with_state(
+            default_limit,
+            rate_limit::limit,
+        )) // Limit every route
+        .layer(middleware::from_fn(problem::problem_details)); // Errors as problem details
+
+    // Probes are neither limited nor turned into problem details, merged into them the API
+    // keeps its fallback
+    routers::health::router(shared_state.clone())
+        .merge(api)
+        .layer(PropagateRequestIdLayer::x_request_id()) // Echo the request id
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid)) // Assign missing request ids
 }
@@ -0,0 +1,163 @@
+// Background delivery of the email outbox
+//
+// Every instance runs a worker. Workers claim due messages with `FOR UPDATE SKIP LOCKED`, so
+// several instances share the queue without sending a message twice. A failed delivery is
+// retried with exponential backoff until `email_max_attempts`, then the message is dead and
+// waits for an admin to requeue it. On shutdown the worker finishes the email it is sending
+// and hands the rest of its batch back to the queue.
+use crate::crud::email_outbox::{self, CHANNEL, Delivery};
+use crate::http::{AppState, utils};
+use rand::Rng;
+use sqlx::postgres::PgListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Messages claimed at once, a worker sends them one after another
+const BATCH_SIZE: i64 = 10;
+
+// Longer than a delivery can take, a crashed worker's messages are due again after it
+const LEASE: time::Duration = time::Duration::minutes(5);
+
+pub async fn run(state: Arc<AppState>) {
+    let poll_interval = Duration::from_secs(state.config.email_outbox_poll_interval);
+
+    // Notified when a message is committed, polling covers retries and a lost connection
+    let mut listener = match PgListener::connect_with(&state.db).await {
+        Ok(mut listener) => match listener.listen(CHANNEL).await {
+            Ok(()) => Some(listener),
+            Err(e) => {
+                log::warn!("Email outbox falls back to polling: {:?}", e);
+                None
+            }
+        },
+        Err(e) => {
+            log::warn!("Email outbox falls back to polling: {:?}", e);
+            None
+        }
+    };
+
+    while !state.shutdown.is_cancelled() {
+        deliver_due(&state).await;
+
+        let wait = async {
+            match listener.as_mut() {
+                Some(listener) => {
+                    tokio::select! {
+                        notification = listener.recv() => {
+                            if let Err(e) = notification {
+                                log::warn!("Email outbox listener failed: {:?}", e);
+                                tokio::time::sleep(poll_interval).await;
+                            }
+                        }
+                        _ = tokio::time::sleep(poll_interval) => (),
+                    }
+                }
+                None => tokio::time::sleep(poll_interval).await,
+            }
+        };
+        tokio::select! {
+            _ = wait => (),
+            _ = state.shutdown.cancelled() => (),
+        }
+    }
+
+    log::info!("Email outbox worker stopped");
+}
+
+// Deliver batches until nothing is due anymore
+async fn deliver_due(state: &AppState) {
+    while !state.shutdown.is_cancelled() {
+        let messages = match email_outbox::claim_due(BATCH_SIZE, LEASE, &state.db).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                log::error!("Failed to claim outbox messages: {:?}", e);
+                return;
+            }
+        };
+        if messages.is_empty() {
+            return;
+        }
+
+        let mut messages = messages.into_iter();
+        for message in messages.by_ref() {
+            deliver(message, state).await;
+            if state.shutdown.is_cancelled() {
+                break;
+            }
+        }
+
+        // Due again right away for the next worker instead of after the lease
+        let unsent: Vec<_> = messages.map(|message| message.message_id).collect();
+        if !unsent.is_empty()
+            && let Err(e) = email_outbox::release(&unsent, &state.db).await
+        {
+            log::error!(
+                "Failed to release {} outbox messages: {:?}",
+                unsent.len(),
+                e
+            );
+        }
+    }
+}
+
+async fn deliver(message: Delivery, state: &AppState) {
+    let sent = utils::send_mail(
+        &message.recipient,
+        &message.subject,
+        &message.html,
+        &message.text,
+        state,
+    )
+    .await;
+
+    let result = match sent {
+        Ok(()) => {
+            log::debug!("Delivered email {}", message.message_id);
+            email_outbox::delete(&message.message_id, &state.db).await
+        }
+        Err(e) => {
+            let error = format!("{:#}", e);
+            let retry_at = (message.attempts < state.config.email_max_attempts)
+                .then(|| time::OffsetDateTime::now_utc() + retry_delay(message.attempts, state));
+            match retry_at {
+                Some(retry_at) => log::warn!(
+                    "Delivery {} of email {} failed, retrying at {}: {}",
+                    message.attempts,
+                    message.message_id,
+                    retry_at,
+                    error
+                ),
+                None => log::error!(
+                    "Giving up on email {} after {} attempts: {}",
+                    message.message_id,
+                    message.attempts,
+                    error
+                ),
+            }
+            email_outbox::record_failure(&message.message_id, &error, retry_at, &state.db).await
+        }
+    };
+
+    // The lease runs out and the message is retried, possibly sent twice
+    if let Err(e) = result {
+        log::error!(
+            "Failed to update email {} after delivery: {:?}",
+            message.message_id,
+            e
+        );
+    }
+}
+
+// Doubles with every attempt up to the maximum, with up to 10% jitter so messages that failed
+// together do not all retry at the same moment
+fn retry_delay(attempts: i32, state: &AppState) -> time::Duration {
+    let config = &state.config;
+    let exponent = attempts.saturating_sub(1).clamp(0, 30) as u32;
+    let delay = config
+        .email_retry_base
+        .saturating_mul(1 << exponent)
+        .min(config.email_retry_max);
+    let jitter = rand::thread_rng().gen_range(0..=delay / 10);
+
+    time::Duration::seconds((delay + jitter) as i64)
+}
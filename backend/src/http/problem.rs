@@ -0,0 +1,168 @@
+// RFC 9457 (formerly 7807) problem details for every error response
+//
+// `Error` renders itself as a `Problem`, but does not know the request it answers. The
+// `problem_details` middleware fills in the request path and id afterwards, and converts the
+// plain text errors axum produces on its own (extractor rejections, unknown routes, wrong
+// methods) into the same format.
+use crate::password_policy::Violation;
+use crate::schemas::validation::ValidationErrors;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header::CONTENT_LENGTH, header::CONTENT_TYPE},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tower_http::request_id::RequestId;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// Bodies of axum's own error responses are a single line of text
+const REJECTION_BODY_LIMIT: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Problem {
+    /// Identifies the kind of problem, `/problems/<code>`
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// The same for every occurrence of the kind
+    pub title: &'static str,
+    pub status: u16,
+    /// What went wrong this time
+    pub detail: String,
+    /// The path of the request that failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Stable, machine-readable name of the kind, e.g. `too_many_requests`
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Invalid fields of the request body
+    #[serde(skip_serializing_if = "ValidationErrors::is_empty")]
+    pub errors: ValidationErrors,
+    /// Password rules a new password breaks
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
+}
+
+impl Problem {
+    pub fn new(
+        status: StatusCode,
+        code: &'static str,
+        title: &'static str,
+        detail: String,
+    ) -> Self {
+        Self {
+            problem_type: format!("/problems/{}", code),
+            title,
+            status: status.as_u16(),
+            detail,
+            instance: None,
+            code,
+            request_id: None,
+            errors: ValidationErrors::default(),
+            violations: Vec::new(),
+        }
+    }
+
+    /// A problem for an error response that only has a status and maybe a text
+    pub fn from_status(status: StatusCode, detail: Option<String>) -> Self {
+        let (code, title) = match status {
+            StatusCode::BAD_REQUEST => ("bad_request", "Bad request"),
+            StatusCode::UNAUTHORIZED => ("unauthorized", "Authentication required"),
+            StatusCode::FORBIDDEN => ("forbidden", "Forbidden"),
+            StatusCode::NOT_FOUND => ("not_found", "Not found"),
+            StatusCode::METHOD_NOT_ALLOWED => ("method_not_allowed", "Method not allowed"),
+            StatusCode::PAYLOAD_TOO_LARGE => ("payload_too_large", "Payload too large"),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                ("unsupported_media_type", "Unsupported media type")
+            }
+            StatusCode::UNPROCESSABLE_ENTITY => ("unprocessable_entity", "Unprocessable entity"),
+            StatusCode::TOO_MANY_REQUESTS => ("too_many_requests", "Too many requests"),
+            s if s.is_server_error() => ("internal_error", "Internal server error"),
+            _ => ("http_error", "Request failed"),
+        };
+        let detail = detail
+            .filter(|detail| !detail.trim().is_empty())
+            .unwrap_or_else(|| title.to_owned());
+
+        Self::new(status, code, title, detail)
+    }
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn body(&self) -> Body {
+        match serde_json::to_vec(self) {
+            Ok(body) => Body::from(body),
+            Err(e) => {
+                log::error!("Failed to serialize problem details: {:?}", e);
+                Body::empty()
+            }
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let mut response = (
+            self.status_code(),
+            [(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON))],
+            self.body(),
+        )
+            .into_response();
+        // Picked up again by `problem_details` to add what only the request knows
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Middleware that completes problem details and turns axum's plain text errors into them.
+/// Layer it inside `SetRequestIdLayer` so the request id is available.
+pub async fn problem_details(request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_owned();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_owned);
+
+    let mut response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let mut problem = match response.extensions_mut().remove::<Problem>() {
+        Some(problem) => problem,
+        None => {
+            // Rejections of axum's extractors and its router fallbacks
+            let body = std::mem::take(response.body_mut());
+            let detail = axum::body::to_bytes(body, REJECTION_BODY_LIMIT)
+                .await
+                .ok()
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+            Problem::from_status(status, detail)
+        }
+    };
+
+    if status.is_server_error() {
+        log::error!(
+            "Request {} to {} failed with {}",
+            request_id.as_deref().unwrap_or("-"),
+            path,
+            status
+        );
+    }
+
+    problem.instance = Some(path);
+    problem.request_id = request_id;
+
+    *response.body_mut() = problem.body();
+    let headers = response.headers_mut();
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    response
+}
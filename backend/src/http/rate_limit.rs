@@ -0,0 +1,343 @@
+// Token bucket rate limiting that can be layered on single routes or whole routers
+use crate::{
+    crud,
+    http::{AppState, dependencies, error::Error as HTTPError, forwarded},
+};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+// The in-memory backend drops full buckets once it tracks this many keys
+const MEMORY_PRUNE_THRESHOLD: usize = 10_000;
+
+/// `burst` requests at once, refilled evenly over `period`, written as `<burst>/<seconds>` or
+/// `<burst>/<unit>` with a unit of `s`, `min`, `h` or `d`, e.g. `10/min`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    fn refill_per_second(&self) -> f64 {
+        self.burst as f64 / self.period.as_secs_f64()
+    }
+
+    // Whole seconds until a bucket with `remaining` tokens holds one again
+    fn retry_after(&self, remaining: f64) -> u64 {
+        (((1.0 - remaining) / self.refill_per_second()).ceil() as u64).max(1)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, period) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected <requests>/<seconds>, got {:?}", s))?;
+        let burst: u32 = burst.trim().parse().map_err(|e| format!("{}", e))?;
+        let seconds = match period.trim() {
+            "s" | "sec" | "second" => 1,
+            "m" | "min" | "minute" => 60,
+            "h" | "hour" => 3600,
+            "d" | "day" => 86_400,
+            period => period.parse().map_err(|_| {
+                format!(
+                    "unknown period {:?}, expected seconds or s, min, h, d",
+                    period
+                )
+            })?,
+        };
+        if burst == 0 || seconds == 0 {
+            return Err("requests and seconds must be positive".to_owned());
+        }
+
+        Ok(Quota {
+            burst,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// Buckets live in the process, every instance limits on its own
+    Memory,
+    /// Buckets live in Postgres and are shared by all instances
+    Postgres,
+}
+
+/// What a bucket is keyed by
+#[derive(Clone, Copy)]
+pub enum KeyBy {
+    /// The address of the client
+    Ip,
+    /// The authenticated user, falling back to the address for anonymous requests
+    User,
+    /// A key derived from the request, None falls back to the address
+    Custom(fn(&Parts) -> Option<String>),
+}
+
+// Outcome of taking a token from a bucket
+struct Decision {
+    allowed: bool,
+    remaining: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // A bucket untouched for its period is full again and can be forgotten
+    period: Duration,
+}
+
+/// Where the buckets of all limits are kept, shared through the app state
+pub struct Store(Buckets);
+
+enum Buckets {
+    Memory(Mutex<HashMap<String, Bucket>>),
+    Postgres(PgPool),
+}
+
+impl Store {
+    pub fn new(backend: Backend, db: &PgPool) -> Self {
+        match backend {
+            Backend::Memory => Store(Buckets::Memory(Mutex::new(HashMap::new()))),
+            Backend::Postgres => Store(Buckets::Postgres(db.clone())),
+        }
+    }
+
+    async fn acquire(&self, key: &str, quota: Quota) -> Result<Decision, HTTPError> {
+        match &self.0 {
+            Buckets::Memory(buckets) => {
+                let mut buckets = buckets.lock().unwrap_or_else(|e| e.into_inner());
+                Ok(take(&mut buckets, key, quota, Instant::now()))
+            }
+            Buckets::Postgres(db) => {
+                let (allowed, remaining) = crud::rate_limit::acquire(
+                    key,
+                    quota.burst as f64,
+                    quota.refill_per_second(),
+                    db,
+                )
+                .await?;
+
+                Ok(Decision { allowed, remaining })
+            }
+        }
+    }
+}
+
+// Take a token from the in-memory bucket of `key`, refilled for the time since its last use
+fn take(buckets: &mut HashMap<String, Bucket>, key: &str, quota: Quota, now: Instant) -> Decision {
+    if buckets.len() >= MEMORY_PRUNE_THRESHOLD {
+        buckets.retain(|_, bucket| now.duration_since(bucket.updated) < bucket.period);
+    }
+
+    let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+        tokens: quota.burst as f64,
+        updated: now,
+        period: quota.period,
+    });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * quota.refill_per_second()).min(quota.burst as f64);
+    bucket.updated = now;
+
+    let allowed = bucket.tokens >= 1.0;
+    if allowed {
+        bucket.tokens -= 1.0;
+    }
+
+    Decision {
+        allowed,
+        remaining: bucket.tokens,
+    }
+}
+
+/// A quota applied to requests of one key, attached with
+/// `axum::middleware::from_fn_with_state(RateLimit::new(..), rate_limit::limit)`
+#[derive(Clone)]
+pub struct RateLimit {
+    name: &'static str,
+    quota: Quota,
+    key: KeyBy,
+    state: Arc<AppState>,
+}
+
+impl RateLimit {
+    /// `name` separates the buckets of different limits sharing a key
+    pub fn new(name: &'static str, quota: Quota, key: KeyBy, state: &Arc<AppState>) -> Self {
+        Self {
+            name,
+            quota,
+            key,
+            state: state.clone(),
+        }
+    }
+
+    fn key(&self, parts: &Parts) -> String {
+        let key = match self.key {
+            KeyBy::Ip => None,
+            KeyBy::User => CookieJar::from_headers(&parts.headers)
+                .get(dependencies::DEFAULT_AUTH)
+                .and_then(|cookie| {
+                    dependencies::AuthUser::claimed_user_id(&self.state, cookie.value())
+                })
+                .map(|user_id| format!("user:{}", user_id)),
+            KeyBy::Custom(key) => key(parts).map(|key| format!("custom:{}", key)),
+        };
+
+        format!(
+            "{}:{}",
+            self.name,
+            key.unwrap_or_else(|| forwarded::client_key(parts, &self.state.config.trusted_proxies))
+        )
+    }
+
+    // Headers as in the IETF draft "RateLimit header fields for HTTP". With several limits on
+    // one route the client sees the one closest to being exhausted.
+    fn set_headers(&self, headers: &mut HeaderMap, remaining: f64) {
+        let remaining = remaining.floor() as u64;
+        let tighter = headers
+            .get(RATELIMIT_REMAINING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .is_some_and(|existing| existing <= remaining);
+        if tighter {
+            return;
+        }
+
+        let reset = ((self.quota.burst as u64 - remaining) as f64 / self.quota.refill_per_second())
+            .ceil() as u64;
+        let policy = format!("{};w={}", self.quota.burst, self.quota.period.as_secs());
+
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.quota.burst));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(reset));
+        if let Ok(policy) = HeaderValue::from_str(&policy) {
+            headers.insert(RATELIMIT_POLICY, policy);
+        }
+    }
+}
+
+pub async fn limit(State(limit): State<RateLimit>, request: Request, next: Next) -> Response {
+    let (parts, body) = request.into_parts();
+    let key = limit.key(&parts);
+
+    let decision = match limit.state.rate_limits.acquire(&key, limit.quota).await {
+        Ok(decision) => decision,
+        // A broken limiter must not take the whole API down with it
+        Err(e) => {
+            log::error!("Rate limiter failed, letting the request through: {:?}", e);
+            return next.run(Request::from_parts(parts, body)).await;
+        }
+    };
+
+    if !decision.allowed {
+        log::debug!("Rate limit {} exceeded", key);
+        let mut response = HTTPError::TooManyRequests {
+            retry_after: limit.quota.retry_after(decision.remaining),
+        }
+        .into_response();
+        limit.set_headers(response.headers_mut(), decision.remaining);
+        return response;
+    }
+
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    limit.set_headers(response.headers_mut(), decision.remaining);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(s: &str) -> Quota {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn quota_parses_seconds_and_units() {
+        let per = |burst, seconds| Quota {
+            burst,
+            period: Duration::from_secs(seconds),
+        };
+        assert_eq!(quota("600/60"), per(600, 60));
+        assert_eq!(quota("10/min"), per(10, 60));
+        assert_eq!(quota(" 5 / h "), per(5, 3600));
+        assert_eq!(quota("100/d"), per(100, 86_400));
+        assert_eq!(quota("1/s"), per(1, 1));
+
+        assert!("0/min".parse::<Quota>().is_err());
+        assert!("10/0".parse::<Quota>().is_err());
+        assert!("10/week".parse::<Quota>().is_err());
+        assert!("10".parse::<Quota>().is_err());
+        assert!("-1/min".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn exhausted_burst_is_denied_with_retry_after() {
+        let quota = quota("3/min");
+        let mut buckets = HashMap::new();
+        let now = Instant::now();
+
+        for remaining in [2.0, 1.0, 0.0] {
+            let decision = take(&mut buckets, "ip:192.0.2.1", quota, now);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let denied = take(&mut buckets, "ip:192.0.2.1", quota, now);
+        assert!(!denied.allowed);
+        // One token every 20 seconds
+        assert_eq!(quota.retry_after(denied.remaining), 20);
+
+        // Other keys have their own bucket
+        assert!(take(&mut buckets, "ip:192.0.2.2", quota, now).allowed);
+    }
+
+    #[test]
+    fn bucket_refills_at_refill_per_second() {
+        let quota = quota("3/min");
+        assert_eq!(quota.refill_per_second(), 0.05);
+        let mut buckets = HashMap::new();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            take(&mut buckets, "key", quota, start);
+        }
+        let early = take(&mut buckets, "key", quota, start + Duration::from_secs(10));
+        assert!(!early.allowed);
+        assert_eq!(quota.retry_after(early.remaining), 10);
+
+        let refilled = take(&mut buckets, "key", quota, start + Duration::from_secs(20));
+        assert!(refilled.allowed);
+        assert!(refilled.remaining.abs() < 1e-9);
+
+        // A bucket never holds more than the burst
+        let later = take(
+            &mut buckets,
+            "key",
+            quota,
+            start + Duration::from_secs(3600),
+        );
+        assert!(later.allowed);
+        assert_eq!(later.remaining, 2.0);
+    }
+}
@@ -0,0 +1,245 @@
+// Router for managing other users, gated by the `users:read` and `users:write` permissions. The
+// outbox of emails requires the admin role.
+use super::auth::issue_password_reset;
+use crate::{
+    crud::{self, login_throttle::Scope},
+    http::{
+        AppState,
+        dependencies::{self, RequirePermission, RequireRole, permissions, roles},
+        error::Error as HTTPError,
+    },
+    schemas::admin::{
+        AdminUserDetail, MailPool, MailStatus, OutboxFilter, OutboxPage, SuspendUser, UserFilter,
+        UserPage,
+    },
+};
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{Router, get, post},
+};
+use std::sync::Arc;
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/admin/users", get(list_users))
+        .route("/admin/users/{user_id}", get(get_user).delete(delete_user))
+        .route("/admin/users/{user_id}/suspend", post(suspend_user))
+        .route("/admin/users/{user_id}/unsuspend", post(unsuspend_user))
+        .route("/admin/users/{user_id}/verify", post(verify_user))
+        .route("/admin/users/{user_id}/unlock", post(unlock_user))
+        .route(
+            "/admin/users/{user_id}/password-reset",
+            post(force_password_reset),
+        )
+        .route("/admin/emails", get(list_emails))
+        .route("/admin/emails/{message_id}/requeue", post(requeue_email))
+        .route("/admin/emails/transport", get(mail_status))
+        .with_state(state)
+}
+
+async fn list_users(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<permissions::UsersRead>,
+    Query(filter): Query<UserFilter>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let page = filter.page.unwrap_or(1).max(1);
+    let per_page = filter
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let (users, total) =
+        crud::admin::list_users(&filter, per_page, offset(page, per_page)?, &state.db).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(UserPage {
+            users,
+            total,
+            page,
+            per_page,
+        }),
+    ))
+}
+
+// The number of rows before `page`, a page beyond any table is rejected instead of overflowing
+fn offset(page: i64, per_page: i64) -> Result<i64, HTTPError> {
+    (page - 1)
+        .checked_mul(per_page)
+        .ok_or_else(|| HTTPError::BadRequest("page is out of range".to_owned()))
+}
+
+async fn get_user(
+    State(state): State<Arc<AppState>>,
+    _: RequirePermission<permissions::UsersRead>,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let user = crud::admin::get_user(&user_id, &state.db).await?;
+    let roles = crud::role::get_roles(&user_id, &state.db).await?;
+    let active_sessions = crud::admin::count_active_sessions(&user_id, &state.db).await?;
+    let locked_until =
+        crud::login_throttle::get_locked_until(&user.username.to_lowercase(), &state.db).await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(AdminUserDetail {
+            user,
+            roles,
+            active_sessions,
+            locked_until,
+        }),
+    ))
+}
+
+async fn suspend_user(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    admin: RequirePermission<permissions::UsersWrite>,
+    Path(user_id): Path<Uuid>,
+    Json(request): Json<SuspendUser>,
+) -> Result<impl IntoResponse, HTTPError> {
+    // Admins suspending themselves would lock them out of this very endpoint
+    if admin.user.user_id == user_id {
+        return Err(HTTPError::Forbidden);
+    }
+
+    crud::admin::suspend_user(&user_id, &request.reason, &state.db).await?;
+    log::info!(
+        "User {} suspended by {}: {}",
+        user_id,
+        admin.user.user_id,
+        request.reason
+    );
+
+    Ok((StatusCode::OK, "User suspended"))
+}
+
+async fn unsuspend_user(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    _: RequirePermission<permissions::UsersWrite>,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::admin::unsuspend_user(&user_id, &state.db).await?;
+    Ok((StatusCode::OK, "User unsuspended"))
+}
+
+// Lifts a lockout after failed logins, client addresses locked on the way stay locked
+async fn unlock_user(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    admin: RequirePermission<permissions::UsersWrite>,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let user = crud::admin::get_user(&user_id, &state.db).await?;
+    crud::login_throttle::clear(Scope::Account, &user.username.to_lowercase(), &state.db).await?;
+    log::info!("User {} unlocked by {}", user_id, admin.user.user_id);
+
+    Ok((StatusCode::OK, "User unlocked"))
+}
+
+async fn verify_user(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    _: RequirePermission<permissions::UsersWrite>,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::admin::verify_user(&user_id, &state.db).await?;
+    Ok((StatusCode::OK, "User verified"))
+}
+
+async fn force_password_reset(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    _: RequirePermission<permissions::UsersWrite>,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let user = crud::user::get_user_by_id(&user_id, &state.db)
+        .await
+        .map_err(|_| HTTPError::NotFound)?;
+
+    issue_password_reset(user, None, state).await?;
+
+    Ok((StatusCode::ACCEPTED, "Password reset email sent"))
+}
+
+async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    _: RequirePermission<permissions::UsersWrite>,
+    Path(user_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::admin::get_user(&user_id, &state.db).await?;
+    crud::user::delete_user(&user_id, &state.db).await?;
+
+    Ok((StatusCode::OK, "Successfully deleted user"))
+}
+
+// Queued emails, by default the dead ones that exhausted their delivery attempts
+async fn list_emails(
+    State(state): State<Arc<AppState>>,
+    _: RequireRole<roles::Admin>,
+    Query(filter): Query<OutboxFilter>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let page = filter.page.unwrap_or(1).max(1);
+    let per_page = filter
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let (messages, total) = crud::email_outbox::list_messages(
+        filter.status,
+        per_page,
+        offset(page, per_page)?,
+        &state.db,
+    )
+    .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(OutboxPage {
+            messages,
+            total,
+            page,
+            per_page,
+        }),
+    ))
+}
+
+async fn requeue_email(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    admin: RequireRole<roles::Admin>,
+    Path(message_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::email_outbox::requeue(&message_id, &state.db).await?;
+    log::info!("Email {} requeued by {}", message_id, admin.user.user_id);
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn mail_status(
+    State(state): State<Arc<AppState>>,
+    _: RequireRole<roles::Admin>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let pool = state.mailer.pool_status().map(|status| MailPool {
+        max_size: status.max_size,
+        size: status.size,
+        available: status.available,
+        waiting: status.waiting,
+    });
+
+    Ok((
+        StatusCode::OK,
+        Json(MailStatus {
+            transport: state.config.mail_transport,
+            pool,
+        }),
+    ))
+}
@@ -1,11 +1,20 @@
 // Router for auth and csrf token generation
 use crate::{
+    crud::{self, email_token::Purpose, login_throttle::Scope},
     http::{
-        dependencies::{self, OptionalAuthUser},
+        AppState,
+        dependencies::{
+            self, AcceptLanguage, AuthUser, ClientInfo, MfaPendingUser, OptionalAuthUser,
+            ValidatedJson,
+        },
         error::Error as HTTPError,
-        utils, AppState,
+        rate_limit::{self, KeyBy, RateLimit},
+        utils,
+    },
+    schemas::{
+        two_factor::TotpCode,
+        users::{ForgotPassword, ResetPassword, User, UserLogin},
     },
-    schemas::users::UserLogin,
 };
 
 use axum_extra::extract::cookie::{Cookie, CookieJar, Expiration};
@@ -13,18 +22,35 @@ use axum_extra::extract::cookie::{Cookie, CookieJar, Expiration};
 use axum::{
     extract::{Json, State},
     http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post, Router},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{Router, get, post},
 };
 use serde_json::json;
 use std::sync::Arc;
+use uuid::Uuid;
 
 pub fn router(state: Arc<AppState>) -> Router {
+    let login_limit = middleware::from_fn_with_state(
+        RateLimit::new("login", state.config.rate_limit_login, KeyBy::Ip, &state),
+        rate_limit::limit,
+    );
+    let email_limit = middleware::from_fn_with_state(
+        RateLimit::new("email", state.config.rate_limit_email, KeyBy::Ip, &state),
+        rate_limit::limit,
+    );
+
     Router::new()
         .route("/", get(ok))
-        .route("/token/get", post(token))
+        .route("/token/get", post(token).layer(login_limit.clone()))
         .route("/token/renew", post(update_token))
+        .route("/token/mfa", post(verify_mfa).layer(login_limit))
         .route("/logout", get(logout))
+        .route(
+            "/auth/password/forgot",
+            post(forgot_password).layer(email_limit),
+        )
+        .route("/auth/password/reset", post(reset_password))
         .with_state(state)
 }
 
@@ -32,10 +58,9 @@ async fn ok() -> impl IntoResponse {
     (StatusCode::OK, axum::Json(json!({ "status": "ok" })))
 }
 
-async fn get_csfr(mut jar: CookieJar) -> CookieJar {
+async fn get_csfr(mut jar: CookieJar, expiration: Expiration) -> CookieJar {
     // Generate a random token
     let csft: String = utils::random_string(32);
-    let expiration = Expiration::from(time::OffsetDateTime::now_utc() + time::Duration::hours(24));
     let server_cookie = Cookie::build(("s_csft", csft.clone()))
         .path("/")
         .secure(true)
@@ -57,65 +82,279 @@ async fn get_csfr(mut jar: CookieJar) -> CookieJar {
     jar
 }
 
+// Set a new access token, the given refresh token and new CSRF tokens
+async fn set_auth_cookies(
+    mut jar: CookieJar,
+    auth_user: &AuthUser,
+    refresh_token: String,
+    state: &AppState,
+) -> Result<CookieJar, HTTPError> {
+    let token = auth_user.to_jwt(state)?;
+    let now = time::OffsetDateTime::now_utc();
+    let refresh_expiration = Expiration::from(now + state.config.refresh_token_lifetime());
+
+    let token_cookie = Cookie::build((dependencies::DEFAULT_AUTH, token))
+        .secure(true)
+        .http_only(true)
+        .expires(Expiration::from(now + state.config.access_token_lifetime()))
+        .path("/")
+        .build();
+    let refresh_cookie = Cookie::build((dependencies::DEFAULT_REFRESH, refresh_token))
+        .secure(true)
+        .http_only(true)
+        .expires(refresh_expiration)
+        .path(dependencies::REFRESH_PATH)
+        .build();
+
+    // CSRF tokens have to outlive the access token, they are needed to refresh it
+    jar = get_csfr(jar, refresh_expiration).await;
+    jar = jar.add(token_cookie).add(refresh_cookie);
+
+    Ok(jar)
+}
+
+// Create a session with its first refresh token and set all cookies for it
+async fn start_session(
+    user_id: Uuid,
+    client: &ClientInfo,
+    jar: CookieJar,
+    state: &AppState,
+) -> Result<CookieJar, HTTPError> {
+    let db = &state.db;
+    let auth_user = AuthUser::create_session(user_id, client, db).await?;
+
+    let refresh_token = utils::random_string(64);
+    crud::refresh_token::create_token(
+        &auth_user.session_id,
+        &utils::hash_token(&refresh_token),
+        time::OffsetDateTime::now_utc() + state.config.refresh_token_lifetime(),
+        db,
+    )
+    .await?;
+
+    set_auth_cookies(jar, &auth_user, refresh_token, state).await
+}
+
 async fn token(
     State(state): State<Arc<AppState>>,
+    client: ClientInfo,
     mut jar: CookieJar,
     maybe_user: OptionalAuthUser,
-    Json(user): Json<UserLogin>,
-) -> Result<impl IntoResponse, HTTPError> {
+    ValidatedJson(user): ValidatedJson<UserLogin>,
+) -> Result<Response, HTTPError> {
     if maybe_user.0.is_some() {
-        return Ok((StatusCode::FOUND, jar));
+        return Ok((StatusCode::FOUND, jar).into_response());
     }
 
     let db = &state.db;
     let UserLogin { username, password } = user;
-    let auth_user = dependencies::auth_user(&username, password, db).await;
-    match auth_user {
-        Ok(auth_user) => {
-            let token = auth_user.to_jwt(&state)?;
-            jar = get_csfr(jar).await;
-            let token_cookie = Cookie::build((dependencies::DEFAULT_AUTH, token.clone()))
-                .secure(true)
-                .http_only(true)
-                .expires(Expiration::from(
-                    time::OffsetDateTime::now_utc() + time::Duration::hours(24),
-                ))
-                .path("/")
-                .build();
-
-            jar = jar.add(token_cookie);
-
-            Ok((StatusCode::OK, jar))
+    dependencies::check_login_throttle(&username, &client, &state).await?;
+
+    let user_id = dependencies::auth_user(&username, password, &state).await;
+    match user_id {
+        Ok(user_id) => {
+            // With 2FA enabled the password only earns a short-lived token for the second step
+            if crud::two_factor::is_enabled(&user_id, db).await? {
+                let mfa_token = MfaPendingUser::to_jwt(user_id, &state)?;
+                let mfa_cookie = Cookie::build((dependencies::DEFAULT_MFA, mfa_token))
+                    .secure(true)
+                    .http_only(true)
+                    .expires(Expiration::from(
+                        time::OffsetDateTime::now_utc() + dependencies::MFA_PENDING_DURATION,
+                    ))
+                    .path(dependencies::MFA_PATH)
+                    .build();
+                jar = jar.add(mfa_cookie);
+
+                return Ok((
+                    StatusCode::ACCEPTED,
+                    jar,
+                    axum::Json(json!({ "mfa_required": true })),
+                )
+                    .into_response());
+            }
+
+            // Only a complete login resets the failures, the password alone does not
+            crud::login_throttle::clear(Scope::Account, &username.to_lowercase(), db).await?;
+            jar = start_session(user_id, &client, jar, &state).await?;
+
+            Ok((StatusCode::OK, jar).into_response())
+        }
+        Err(HTTPError::Unauthorized) => {
+            dependencies::record_login_failure(&username, &client, &state).await?;
+            Err(HTTPError::Unauthorized)
         }
         Err(e) => Err(e),
     }
 }
 
+async fn verify_mfa(
+    State(state): State<Arc<AppState>>,
+    client: ClientInfo,
+    mut jar: CookieJar,
+    pending: MfaPendingUser,
+    Json(request): Json<TotpCode>,
+) -> Result<impl IntoResponse, HTTPError> {
+    // Six digits are quickly guessed, the second factor is throttled like the password
+    let user = crud::user::get_user_by_id(&pending.user_id, &state.db).await?;
+    dependencies::check_login_throttle(&user.username, &client, &state).await?;
+
+    let verified =
+        dependencies::verify_second_factor(&pending.user_id, &request.code, &state.db).await;
+    if let Err(HTTPError::Unauthorized) = verified {
+        dependencies::record_login_failure(&user.username, &client, &state).await?;
+    }
+    verified?;
+    crud::login_throttle::clear(Scope::Account, &user.username.to_lowercase(), &state.db).await?;
+
+    jar = jar.remove(
+        Cookie::build(dependencies::DEFAULT_MFA)
+            .path(dependencies::MFA_PATH)
+            .build(),
+    );
+    jar = start_session(pending.user_id, &client, jar, &state).await?;
+
+    Ok((StatusCode::OK, jar))
+}
+
 async fn update_token(
     State(state): State<Arc<AppState>>,
     _: dependencies::CsrfValidator,
     mut jar: CookieJar,
-    user: dependencies::AuthUser,
 ) -> Result<impl IntoResponse, HTTPError> {
-    let token = user.to_jwt(&state)?;
-    let token_cookie = Cookie::build((dependencies::DEFAULT_AUTH, token.clone()))
-        .secure(true)
-        .http_only(true)
-        .expires(Expiration::from(
-            time::OffsetDateTime::now_utc() + time::Duration::hours(24),
-        ))
-        .path("/")
-        .build();
-    jar = get_csfr(jar).await;
-    jar = jar.add(token_cookie);
+    let presented = jar
+        .get(dependencies::DEFAULT_REFRESH)
+        .map(|cookie| cookie.value().to_owned())
+        .ok_or_else(|| {
+            log::debug!("Refresh cookie is missing");
+            HTTPError::Unauthorized
+        })?;
+
+    let refresh_token = utils::random_string(64);
+    let (user_id, session_id) = crud::refresh_token::rotate_token(
+        &utils::hash_token(&presented),
+        &utils::hash_token(&refresh_token),
+        &state.db,
+    )
+    .await?;
+
+    let auth_user = AuthUser {
+        user_id,
+        session_id,
+    };
+    jar = set_auth_cookies(jar, &auth_user, refresh_token, &state).await?;
 
     Ok((StatusCode::OK, jar))
 }
 
-async fn logout(mut jar: CookieJar) -> impl IntoResponse {
-    jar = jar
-        .remove(Cookie::from("x_csft"))
+pub(super) fn remove_auth_cookies(jar: CookieJar) -> CookieJar {
+    jar.remove(Cookie::from("x_csft"))
         .remove(Cookie::from("s_csft"))
-        .remove(Cookie::from(dependencies::DEFAULT_AUTH));
-    (StatusCode::OK, jar)
+        .remove(Cookie::from(dependencies::DEFAULT_AUTH))
+        .remove(
+            Cookie::build(dependencies::DEFAULT_REFRESH)
+                .path(dependencies::REFRESH_PATH)
+                .build(),
+        )
+}
+
+async fn logout(
+    State(state): State<Arc<AppState>>,
+    maybe_user: OptionalAuthUser,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, HTTPError> {
+    // Revoke the session server-side, removing the cookie alone would leave a copied token valid
+    if let Some(user) = maybe_user.0 {
+        crud::session::revoke_session(&user.session_id, &user.user_id, &state.db).await?;
+    }
+
+    Ok((StatusCode::OK, remove_auth_cookies(jar)))
+}
+
+async fn forgot_password(
+    State(state): State<Arc<AppState>>,
+    AcceptLanguage(locale): AcceptLanguage,
+    ValidatedJson(request): ValidatedJson<ForgotPassword>,
+) -> impl IntoResponse {
+    // Lookup and mail happen in the background, so neither the response nor its timing
+    // tells the caller whether the address belongs to an account.
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        match crud::user::get_user_by_email(&request.email, &state.db).await {
+            Ok(user) => {
+                if let Err(e) = issue_password_reset(user, locale, state).await {
+                    log::error!("Failed to issue password reset: {:?}", e);
+                }
+            }
+            Err(_) => log::debug!("Password reset requested for unknown email"),
+        }
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        "If the address belongs to an account, a reset link has been sent",
+    )
+}
+
+// The token and its email are stored together, the outbox worker delivers the email. It is
+// written in the locale of the user, or else `fallback_locale`.
+pub(super) async fn issue_password_reset(
+    user: User,
+    fallback_locale: Option<String>,
+    state: Arc<AppState>,
+) -> Result<(), HTTPError> {
+    let mut tx = state.db.begin().await?;
+    let token = utils::issue_email_token(&user.id, Purpose::ResetPassword, &mut tx).await?;
+    utils::queue_password_reset(
+        &user.email,
+        user.locale.as_deref().or(fallback_locale.as_deref()),
+        &token.to_string(),
+        Purpose::ResetPassword.lifetime(),
+        &state,
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+async fn reset_password(
+    State(state): State<Arc<AppState>>,
+    ValidatedJson(request): ValidatedJson<ResetPassword>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let ResetPassword {
+        token,
+        new_password,
+    } = request;
+
+    let token = utils::EmailToken::parse(&token).ok_or(HTTPError::Forbidden)?;
+
+    // The policy check and the hash are slow, they run before the transaction. A rejected
+    // password leaves the token usable for another try.
+    let user_id = crud::email_token::check_token(
+        &token.id,
+        Purpose::ResetPassword,
+        &token.hash(),
+        &mut *state.db.acquire().await?,
+    )
+    .await?;
+    let user = crud::user::get_user_by_id(&user_id, &state.db).await?;
+    dependencies::check_password_policy(&new_password, &user.username, &user.email, &state).await?;
+    let pw_hash = dependencies::hash_password(new_password, &state).await?;
+
+    // Consuming checks the token again, it may have been used meanwhile
+    let mut tx = state.db.begin().await?;
+    let consumed_by =
+        crud::email_token::consume_token(&token.id, Purpose::ResetPassword, &token.hash(), &mut tx)
+            .await?;
+    if consumed_by != user_id {
+        return Err(HTTPError::Forbidden);
+    }
+    crud::user::update_password(&user_id, &pw_hash, &mut tx).await?;
+    crud::email_token::invalidate_tokens(&user_id, Purpose::ResetPassword, &mut tx).await?;
+    crud::session::revoke_all_sessions(&user_id, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::OK, "Password reset successfully"))
 }
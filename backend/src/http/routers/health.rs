@@ -0,0 +1,121 @@
+// Probes for load balancers and orchestrators
+//
+// `/health/live` only tells that the process serves requests, a restart would not fix a
+// broken dependency. `/health/ready` checks the dependencies and answers 503 while one of them
+// is down or the server shuts down, so traffic goes to other instances. Neither is rate
+// limited, and the 503 carries the report instead of problem details.
+use crate::{
+    crud,
+    http::AppState,
+    schemas::health::{Component, ComponentStatus, Readiness, ReadinessReport},
+};
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{Router, get},
+};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// A dependency slower than this counts as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .with_state(state)
+}
+
+async fn live() -> impl IntoResponse {
+    (StatusCode::OK, Json(json!({ "status": "ok" })))
+}
+
+async fn ready(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    if state.shutdown.is_cancelled() {
+        let report = ReadinessReport {
+            status: Readiness::ShuttingDown,
+            components: BTreeMap::new(),
+        };
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(report));
+    }
+
+    let database = check("database", async {
+        crud::health::ping(&state.db)
+            .await
+            .map_err(|e| failed("database", e))
+    });
+    let migrations = check("migrations", async {
+        match crud::health::pending_migrations(&state.db).await {
+            Ok(0) => Ok(()),
+            Ok(pending) => Err(format!("{} migrations pending", pending)),
+            Err(e) => Err(failed("migrations", e)),
+        }
+    });
+    let smtp = async {
+        match state.config.health_check_smtp {
+            true => Some(
+                check("smtp", async {
+                    state.mailer.check().await.map_err(|e| failed("smtp", e))
+                })
+                .await,
+            ),
+            false => None,
+        }
+    };
+    let (database, migrations, smtp) = tokio::join!(database, migrations, smtp);
+
+    let mut components = BTreeMap::from([("database", database), ("migrations", migrations)]);
+    if let Some(smtp) = smtp {
+        components.insert("smtp", smtp);
+    }
+    let status = match components
+        .values()
+        .all(|component| component.status == ComponentStatus::Up)
+    {
+        true => Readiness::Ready,
+        false => Readiness::NotReady,
+    };
+    let code = match status {
+        Readiness::Ready => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (code, Json(ReadinessReport { status, components }))
+}
+
+// Run one check, the error is the message shown in the report
+async fn check(name: &str, check: impl Future<Output = Result<(), String>>) -> Component {
+    let start = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) => Some(error),
+        Err(_) => {
+            log::warn!("Readiness check {} timed out", name);
+            Some("timed out".to_owned())
+        }
+    };
+    Component {
+        status: match error {
+            None => ComponentStatus::Up,
+            Some(_) => ComponentStatus::Down,
+        },
+        latency_ms,
+        error,
+    }
+}
+
+// Connection strings and server names stay out of the public report
+fn failed(name: &str, error: impl std::fmt::Display) -> String {
+    log::warn!("Readiness check {} failed: {:#}", name, error);
+    "check failed".to_owned()
+}
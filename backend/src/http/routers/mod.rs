@@ -1,2 +1,6 @@
+pub mod admin;
 pub mod auth;
+pub mod health;
+pub mod roles;
+pub mod two_factor;
 pub mod user;
@@ -0,0 +1,69 @@
+// Router for inspecting and granting roles
+use crate::{
+    crud,
+    http::{
+        AppState,
+        dependencies::{self, RequirePermission, permissions},
+        error::Error as HTTPError,
+    },
+    schemas::roles::{GrantRole, UserRoles},
+};
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{Router, delete, get, post},
+};
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/users/me/roles", get(my_roles))
+        .route("/admin/users/{user_id}/roles", post(grant_role))
+        .route("/admin/users/{user_id}/roles/{role}", delete(revoke_role))
+        .with_state(state)
+}
+
+async fn my_roles(
+    State(state): State<Arc<AppState>>,
+    auth_user: dependencies::AuthUser,
+) -> Result<impl IntoResponse, HTTPError> {
+    let roles = crud::role::get_roles(&auth_user.user_id, &state.db).await?;
+    let permissions = crud::role::get_permissions(&auth_user.user_id, &state.db).await?;
+
+    Ok((StatusCode::OK, Json(UserRoles { roles, permissions })))
+}
+
+async fn grant_role(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    granter: RequirePermission<permissions::RolesGrant>,
+    Path(user_id): Path<Uuid>,
+    Json(request): Json<GrantRole>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::role::grant_role(&user_id, &request.role, &state.db).await?;
+    log::info!(
+        "Role {} granted to {} by {}",
+        request.role,
+        user_id,
+        granter.user.user_id
+    );
+    Ok((StatusCode::OK, "Role granted"))
+}
+
+async fn revoke_role(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    revoker: RequirePermission<permissions::RolesGrant>,
+    Path((user_id, role)): Path<(Uuid, String)>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::role::revoke_role(&user_id, &role, &state.db).await?;
+    log::info!(
+        "Role {} revoked from {} by {}",
+        role,
+        user_id,
+        revoker.user.user_id
+    );
+    Ok((StatusCode::OK, "Role revoked"))
+}
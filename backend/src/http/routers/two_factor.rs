@@ -0,0 +1,97 @@
+// Router for enrolling in and leaving TOTP two-factor authentication
+use crate::{
+    crud,
+    http::{AppState, dependencies, error::Error as HTTPError, utils},
+    schemas::two_factor::{DisableTwoFactor, RecoveryCodes, TotpCode, TotpSetup},
+    totp,
+};
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{Router, post},
+};
+use std::sync::Arc;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/users/me/2fa/setup", post(setup))
+        .route("/users/me/2fa/confirm", post(confirm))
+        .route("/users/me/2fa/disable", post(disable))
+        .with_state(state)
+}
+
+// Recovery codes look like `k3j9d-0qpwz`, they are shown once and only their hashes are stored
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = utils::random_string(10).to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+async fn setup(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    auth_user: dependencies::AuthUser,
+) -> Result<impl IntoResponse, HTTPError> {
+    let user = crud::user::get_user_by_id(&auth_user.user_id, &state.db).await?;
+
+    let secret = totp::generate_secret();
+    crud::two_factor::set_pending_secret(&user.id, &totp::encode_secret(&secret), &state.db)
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(TotpSetup {
+            secret: totp::encode_secret(&secret),
+            otpauth_uri: totp::provisioning_uri(&state.config.totp_issuer, &user.username, &secret),
+        }),
+    ))
+}
+
+async fn confirm(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    auth_user: dependencies::AuthUser,
+    Json(request): Json<TotpCode>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let secret = match crud::two_factor::get_secret(&auth_user.user_id, &state.db).await? {
+        Some((secret, false)) => secret,
+        Some((_, true)) => return Err(HTTPError::Conflict),
+        None => return Err(HTTPError::NotFound),
+    };
+    let secret = totp::decode_secret(&secret).ok_or(HTTPError::InternalServerError)?;
+
+    let now = time::OffsetDateTime::now_utc().unix_timestamp() as u64;
+    let step = totp::verify(&secret, request.code.trim(), now).ok_or(HTTPError::Forbidden)?;
+
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| utils::hash_token(&code.replace('-', "")))
+        .collect();
+    crud::two_factor::confirm_secret(&auth_user.user_id, step as i64, &hashes, &state.db).await?;
+
+    Ok((StatusCode::OK, Json(RecoveryCodes { recovery_codes })))
+}
+
+async fn disable(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    auth_user: dependencies::AuthUser,
+    Json(request): Json<DisableTwoFactor>,
+) -> Result<impl IntoResponse, HTTPError> {
+    // Re-authenticate with both factors, a hijacked session alone must not remove 2FA
+    let user = crud::user::get_user_by_id(&auth_user.user_id, &state.db).await?;
+    let (_, password_hash) = crud::user::get_hash(&user.username, &state.db).await?;
+    dependencies::validate_password(request.password, &password_hash, &state).await?;
+    dependencies::verify_second_factor(&user.id, &request.code, &state.db).await?;
+
+    crud::two_factor::disable(&user.id, &state.db).await?;
+
+    Ok((StatusCode::OK, "Two-factor authentication disabled"))
+}
@@ -1,24 +1,67 @@
+use super::auth::remove_auth_cookies;
 use crate::{
-    crud,
-    http::{AppState, dependencies, error::Error as HTTPError},
-    schemas::users::{NewUser, UpdatePassword, User},
+    crud::{self, email_token::Purpose},
+    http::{
+        AppState,
+        dependencies::{self, AcceptLanguage, ValidatedJson},
+        error::Error as HTTPError,
+        rate_limit::{self, KeyBy, RateLimit},
+        utils,
+    },
+    schemas::users::{
+        ChangeEmail, EmailChangeToken, NewUser, ResendVerification, UpdateLocale, UpdatePassword,
+        User,
+    },
 };
+use axum_extra::extract::cookie::CookieJar;
+
 use axum::{
     extract::{Json, Path, State},
     http::StatusCode,
-    response::IntoResponse,
+    middleware,
+    response::{IntoResponse, Redirect},
     routing::{Router, delete, get, post},
 };
 use std::sync::Arc;
 use uuid::Uuid;
 
+// Minimum time between two verification emails to the same address
+const VERIFICATION_RESEND_INTERVAL: time::Duration = time::Duration::minutes(5);
+
 pub fn router(state: Arc<AppState>) -> Router {
+    // Every one of these sends an email
+    let email_limit = |key| {
+        middleware::from_fn_with_state(
+            RateLimit::new("email", state.config.rate_limit_email, key, &state),
+            rate_limit::limit,
+        )
+    };
+
     Router::new()
-        .route("/users/create-user", post(create_user))
+        .route(
+            "/users/create-user",
+            post(create_user).layer(email_limit(KeyBy::Ip)),
+        )
         .route("/users/delete-user", delete(delete_user))
         .route("/users/me", get(me))
         .route("/users/me/update-password", post(update_password))
-        .route("/users/verify/{username}/{token}", post(verify_user))
+        .route("/users/me/locale", post(update_locale))
+        .route(
+            "/users/me/email",
+            post(change_email).layer(email_limit(KeyBy::User)),
+        )
+        .route("/users/me/email/confirm", post(confirm_email_change))
+        .route("/users/me/email/cancel", post(cancel_email_change))
+        .route(
+            "/users/me/sessions",
+            get(list_sessions).delete(revoke_all_sessions),
+        )
+        .route("/users/me/sessions/{session_id}", delete(revoke_session))
+        .route("/users/verify/{token}", get(verify_user))
+        .route(
+            "/users/verify/resend",
+            post(resend_verification).layer(email_limit(KeyBy::Ip)),
+        )
         .with_state(state)
 }
 
@@ -34,6 +77,7 @@ async fn me(
                 username: String::from(""),
                 email: String::from(""),
                 verified: true,
+                locale: None,
             };
             return Ok((StatusCode::NO_CONTENT, Json(user)));
         }
@@ -45,57 +89,340 @@ async fn me(
 
 async fn create_user(
     State(state): State<Arc<AppState>>,
-    Json(user): Json<NewUser>,
+    AcceptLanguage(accepted): AcceptLanguage,
+    ValidatedJson(user): ValidatedJson<NewUser>,
 ) -> Result<impl IntoResponse, HTTPError> {
     log::debug!("New user creation started");
     let NewUser {
         username,
         email,
         password,
+        locale,
     } = user;
+    let locale = locale.or(accepted);
+
+    dependencies::check_password_policy(&password, &username, &email, &state).await?;
+    let password_hash = dependencies::hash_password(password, &state).await?;
+
+    let result = register(&username, &email, &password_hash, locale.as_deref(), &state).await;
+    if !state.config.registration_enumeration_safe {
+        result?;
+        log::debug!("Successfully created new user");
+        return Ok((
+            StatusCode::CREATED,
+            "User created, check your email to verify the account",
+        ));
+    }
+
+    // Taken names and addresses look like a successful registration, only the owner of the
+    // address learns what happened
+    match result {
+        Ok(()) => log::debug!("Successfully created new user"),
+        Err(HTTPError::Taken { .. }) => {
+            let tasks = state.tasks.clone();
+            tasks.spawn(notify_duplicate_registration(
+                username, email, locale, state,
+            ));
+        }
+        Err(e) => return Err(e),
+    }
+    Ok((
+        StatusCode::ACCEPTED,
+        "Check your email to continue the registration",
+    ))
+}
 
-    let password_hash = dependencies::hash_password(password)?;
+// The account and its verification email are committed together
+async fn register(
+    username: &str,
+    email: &str,
+    password_hash: &str,
+    locale: Option<&str>,
+    state: &AppState,
+) -> Result<(), HTTPError> {
+    let mut tx = state.db.begin().await?;
+    let user_id = crud::user::create_user(username, email, password_hash, locale, &mut tx).await?;
+    let token = utils::issue_email_token(&user_id, Purpose::VerifyEmail, &mut tx).await?;
+    utils::queue_verification(email, locale, &token.to_string(), state, &mut tx).await?;
+    tx.commit().await?;
 
-    _ = crud::user::create_user(&username, &email, &password_hash, state).await?;
-    log::debug!("Successfully created new user");
-    Ok((StatusCode::CREATED, "User created successfully"))
+    Ok(())
+}
+
+async fn notify_duplicate_registration(
+    username: String,
+    email: String,
+    locale: Option<String>,
+    state: Arc<AppState>,
+) {
+    let result = async {
+        let mut conn = state.db.acquire().await?;
+        // The address may be taken even if the constraint that fired was the username's
+        match crud::user::get_user_by_email(&email, &state.db).await {
+            Ok(owner) => {
+                utils::queue_registration_attempt(
+                    &email,
+                    owner.locale.as_deref().or(locale.as_deref()),
+                    &state,
+                    &mut conn,
+                )
+                .await
+            }
+            Err(_) => {
+                utils::queue_username_taken(&email, locale.as_deref(), &username, &state, &mut conn)
+                    .await
+            }
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to queue duplicate registration mail: {:?}", e);
+    }
 }
 
 async fn update_password(
     State(state): State<Arc<AppState>>,
     auth_user: dependencies::AuthUser,
-    Json(update_struct): Json<UpdatePassword>,
+    jar: CookieJar,
+    ValidatedJson(update_struct): ValidatedJson<UpdatePassword>,
 ) -> Result<impl IntoResponse, HTTPError> {
     let user = crud::user::get_user_by_id(&auth_user.user_id, &state.db).await?;
     let old_hash = crud::user::get_hash(&user.username, &state.db).await?.1;
-    let pw_hash = dependencies::hash_password(update_struct.new_password)?;
 
-    dependencies::validate_password(update_struct.old_password, &old_hash)?;
-    if crud::user::update_password(&auth_user.user_id, &pw_hash, &state.db).await? == true {
-        Ok(StatusCode::OK)
+    dependencies::validate_password(update_struct.old_password, &old_hash, &state).await?;
+    dependencies::check_password_policy(
+        &update_struct.new_password,
+        &user.username,
+        &user.email,
+        &state,
+    )
+    .await?;
+    let pw_hash = dependencies::hash_password(update_struct.new_password, &state).await?;
+    let mut tx = state.db.begin().await?;
+    if crud::user::update_password(&auth_user.user_id, &pw_hash, &mut tx).await? {
+        // Every session is revoked, this one included, the user logs in with the new password
+        crud::session::revoke_all_sessions(&auth_user.user_id, &mut tx).await?;
+        tx.commit().await?;
+        Ok((StatusCode::OK, remove_auth_cookies(jar)))
     } else {
         log::error!("Failed to update password");
         Err(HTTPError::InternalServerError)
     }
 }
 
+async fn update_locale(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    auth_user: dependencies::AuthUser,
+    ValidatedJson(request): ValidatedJson<UpdateLocale>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::user::update_locale(&auth_user.user_id, request.locale.as_deref(), &state.db).await?;
+    Ok(StatusCode::OK)
+}
+
+async fn change_email(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    auth_user: dependencies::AuthUser,
+    ValidatedJson(request): ValidatedJson<ChangeEmail>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let user = crud::user::get_user_by_id(&auth_user.user_id, &state.db).await?;
+    let password_hash = crud::user::get_hash(&user.username, &state.db).await?.1;
+    dependencies::validate_password(request.password, &password_hash, &state).await?;
+
+    // Also catches the current address, the collation compares case insensitively
+    if crud::user::check_email(&request.new_email, &state.db).await {
+        return Err(HTTPError::Taken { field: "new_email" });
+    }
+
+    let mut tx = state.db.begin().await?;
+    let (confirm, cancel) =
+        utils::issue_email_change(&user.id, &request.new_email, &mut tx).await?;
+    utils::queue_email_change(
+        &request.new_email,
+        user.locale.as_deref(),
+        &confirm.to_string(),
+        Purpose::ChangeEmail.lifetime(),
+        &state,
+        &mut tx,
+    )
+    .await?;
+    utils::queue_email_change_notice(
+        &user.email,
+        user.locale.as_deref(),
+        &request.new_email,
+        &cancel.to_string(),
+        &state,
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        "Check the new address to confirm the change",
+    ))
+}
+
+// Posted by the page the link to the new address opens, a GET would let link scanners in
+// mailboxes confirm the change. The address is only swapped here, another account may have
+// taken it in the meantime, which is reported as a conflict.
+async fn confirm_email_change(
+    State(state): State<Arc<AppState>>,
+    ValidatedJson(request): ValidatedJson<EmailChangeToken>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let token = utils::EmailToken::parse(&request.token).ok_or(HTTPError::Forbidden)?;
+
+    let mut tx = state.db.begin().await?;
+    let user_id =
+        crud::email_token::consume_token(&token.id, Purpose::ChangeEmail, &token.hash(), &mut tx)
+            .await?;
+    let new_email = crud::email_token::get_new_email(&token.id, &mut tx).await?;
+    crud::user::update_email(&user_id, &new_email, &mut tx).await?;
+    crud::email_token::invalidate_tokens(&user_id, Purpose::CancelEmailChange, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::OK, "Email address changed"))
+}
+
+// Posted by the page the link to the old address opens
+async fn cancel_email_change(
+    State(state): State<Arc<AppState>>,
+    ValidatedJson(request): ValidatedJson<EmailChangeToken>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let token = utils::EmailToken::parse(&request.token).ok_or(HTTPError::Forbidden)?;
+
+    let mut tx = state.db.begin().await?;
+    let user_id = crud::email_token::consume_token(
+        &token.id,
+        Purpose::CancelEmailChange,
+        &token.hash(),
+        &mut tx,
+    )
+    .await?;
+    crud::email_token::invalidate_tokens(&user_id, Purpose::ChangeEmail, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::OK, "Email change cancelled"))
+}
+
+// Opened from the link in the verification email, so the result is a redirect to the frontend
 async fn verify_user(
     State(state): State<Arc<AppState>>,
-    Path((username, token)): Path<(String, String)>,
+    AcceptLanguage(accepted): AcceptLanguage,
+    Path(token): Path<String>,
 ) -> Result<impl IntoResponse, HTTPError> {
-    if crud::user::get_verification_token(&username, &state.db).await? == token {
-        _ = crud::user::verify_user(&username, &state.db).await?;
-        Ok((StatusCode::OK, "User successfully verified"))
-    } else {
-        Err(HTTPError::Forbidden)
+    let verified = match utils::EmailToken::parse(&token) {
+        Some(token) => {
+            // Consuming and verifying in one transaction, the cleanup task must never see a
+            // consumed token of a user that is still unverified
+            let mut tx = state.db.begin().await?;
+            match crud::email_token::consume_token(
+                &token.id,
+                Purpose::VerifyEmail,
+                &token.hash(),
+                &mut tx,
+            )
+            .await
+            {
+                Ok(user_id) => {
+                    let user = crud::user::verify_user(&user_id, &mut tx).await?;
+                    utils::queue_welcome(
+                        &user.email,
+                        user.locale.as_deref().or(accepted.as_deref()),
+                        &user.username,
+                        &state,
+                        &mut tx,
+                    )
+                    .await?;
+                    tx.commit().await?;
+                    true
+                }
+                Err(HTTPError::Forbidden) => false,
+                Err(e) => return Err(e),
+            }
+        }
+        None => false,
+    };
+
+    Ok(Redirect::to(&state.links.login_with("verified", verified)))
+}
+
+async fn resend_verification(
+    State(state): State<Arc<AppState>>,
+    AcceptLanguage(accepted): AcceptLanguage,
+    ValidatedJson(request): ValidatedJson<ResendVerification>,
+) -> Result<impl IntoResponse, HTTPError> {
+    let sent_before = time::OffsetDateTime::now_utc() - VERIFICATION_RESEND_INTERVAL;
+
+    let mut tx = state.db.begin().await?;
+    let claimed =
+        crud::user::claim_verification_resend(&request.email, sent_before, &mut tx).await?;
+    if let Some((user_id, locale)) = claimed {
+        let token = utils::issue_email_token(&user_id, Purpose::VerifyEmail, &mut tx).await?;
+        utils::queue_verification(
+            &request.email,
+            locale.as_deref().or(accepted.as_deref()),
+            &token.to_string(),
+            &state,
+            &mut tx,
+        )
+        .await?;
     }
+    tx.commit().await?;
+
+    // Same answer for unknown, verified and rate limited addresses
+    Ok((
+        StatusCode::ACCEPTED,
+        "If the address belongs to an unverified account, a new verification email has been sent",
+    ))
 }
 
 async fn delete_user(
     State(state): State<Arc<AppState>>,
     _: dependencies::CsrfValidator,
     auth_user: dependencies::AuthUser,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, HTTPError> {
+    // Sessions are removed together with the user by `on delete cascade`
+    crud::user::delete_user(&auth_user.user_id, &state.db).await?;
+    Ok((
+        StatusCode::OK,
+        remove_auth_cookies(jar),
+        "Successfully deleted user",
+    ))
+}
+
+async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    auth_user: dependencies::AuthUser,
+) -> Result<impl IntoResponse, HTTPError> {
+    let sessions =
+        crud::session::list_sessions(&auth_user.user_id, &auth_user.session_id, &state.db).await?;
+    Ok((StatusCode::OK, Json(sessions)))
+}
+
+async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    auth_user: dependencies::AuthUser,
+    Path(session_id): Path<Uuid>,
+) -> Result<impl IntoResponse, HTTPError> {
+    crud::session::revoke_session(&session_id, &auth_user.user_id, &state.db).await?;
+    Ok((StatusCode::OK, "Session revoked"))
+}
+
+async fn revoke_all_sessions(
+    State(state): State<Arc<AppState>>,
+    _: dependencies::CsrfValidator,
+    auth_user: dependencies::AuthUser,
+    jar: CookieJar,
 ) -> Result<impl IntoResponse, HTTPError> {
-    _ = crud::user::delete_user(&auth_user.user_id, &state.db).await?;
-    Ok((StatusCode::OK, "Successfully deleted user"))
+    let mut conn = state.db.acquire().await?;
+    crud::session::revoke_all_sessions(&auth_user.user_id, &mut conn).await?;
+    Ok((
+        StatusCode::OK,
+        remove_auth_cookies(jar),
+        "Logged out of all sessions",
+    ))
 }
@@ -0,0 +1,104 @@
+// Stopping without losing work
+//
+// On SIGTERM or SIGINT `AppState::shutdown` is cancelled and, after `shutdown_delay`, the
+// listener stops accepting connections. Background workers watch the token and stop after their
+// current unit of work, in-flight requests and tasks in `AppState::tasks` get until
+// `shutdown_delay` plus `shutdown_timeout` to finish. Whatever is still running then is aborted
+// when the process exits, the summary tells what. Emails survive either way, they stay in the
+// outbox until delivered.
+use crate::http::AppState;
+use anyhow::Context;
+use axum::{extract::Request, extract::State, middleware::Next, response::Response};
+use std::{future::Future, io, time::Duration};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    time::{Instant, timeout, timeout_at},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+// Closing the pools waits for connections still in use by aborted work
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Cancel the token on SIGTERM, sent by systemd, Docker and Kubernetes, or SIGINT
+pub async fn cancel_on_signal(shutdown: CancellationToken) {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                log::error!("Failed to listen for SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT, shutting down"),
+        _ = terminate => log::info!("Received SIGTERM, shutting down"),
+        _ = shutdown.cancelled() => return,
+    }
+    shutdown.cancel();
+}
+
+/// Count in-flight requests, so the shutdown can tell how many it aborted
+pub async fn track_request(
+    State(requests): State<TaskTracker>,
+    request: Request,
+    next: Next,
+) -> Response {
+    requests.track_future(next.run(request)).await
+}
+
+/// Run the server until the shutdown, then wait for requests and background tasks until the
+/// deadline and close the pools
+pub async fn drain(
+    server: impl Future<Output = io::Result<()>>,
+    requests: TaskTracker,
+    state: &AppState,
+) -> anyhow::Result<()> {
+    tokio::pin!(server);
+    tokio::select! {
+        result = &mut server => {
+            result.context("Error running the server")?;
+            // The server only stops on its own once the shutdown started
+        }
+        _ = state.shutdown.cancelled() => (),
+    }
+    let grace = state.config.shutdown_delay + state.config.shutdown_timeout;
+    let deadline = Instant::now() + Duration::from_secs(grace);
+
+    // The listener is closed, connections finish their current request
+    state.tasks.close();
+    let (server, tasks) = tokio::join!(
+        timeout_at(deadline, server),
+        timeout_at(deadline, state.tasks.wait())
+    );
+    if let Ok(result) = server {
+        result.context("Error running the server")?;
+    }
+    let aborted_requests = requests.len();
+    let aborted_tasks = match tasks {
+        Ok(()) => 0,
+        Err(_) => state.tasks.len(),
+    };
+
+    if timeout(CLOSE_TIMEOUT, state.mailer.close()).await.is_err() {
+        log::warn!("Timed out closing the mail transport");
+    }
+    if timeout(CLOSE_TIMEOUT, state.db.close()).await.is_err() {
+        log::warn!("Timed out closing the database pool");
+    }
+
+    match aborted_requests + aborted_tasks {
+        0 => log::info!("Shutdown complete, all requests and background tasks finished"),
+        _ => log::warn!(
+            "Shutdown deadline of {}s passed, aborted {} in-flight requests and {} background tasks",
+            grace,
+            aborted_requests,
+            aborted_tasks
+        ),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,173 @@
+// HTTPS without a proxy in front
+//
+// The certificate and key are read again every `tls_reload_interval` and swapped in when they
+// changed, so a rotation by cert-manager or certbot needs no restart. Connections already open
+// keep the certificate they were made with. A rotation that cannot be loaded, e.g. because only
+// one of the files was written yet, is logged and the previous certificate stays in use.
+use anyhow::Context;
+use axum::serve::Listener;
+use futures::{StreamExt, future::BoxFuture, stream::FuturesUnordered};
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+    server::TlsStream,
+};
+use tokio_util::sync::CancellationToken;
+
+// A client that does not finish the handshake in time is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Handshake = BoxFuture<'static, (io::Result<TlsStream<TcpStream>>, SocketAddr)>;
+
+/// The certificate and key files, and the server config last loaded from them
+pub struct Certificate {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl Certificate {
+    pub fn load(cert: PathBuf, key: PathBuf) -> anyhow::Result<Self> {
+        let (cert_pem, key_pem) = read(&cert, &key)?;
+        let config = server_config(&cert_pem, &key_pem)?;
+
+        Ok(Self {
+            cert,
+            key,
+            current: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    fn current(&self) -> Arc<ServerConfig> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Check the files for changes until the shutdown
+    pub async fn watch(self: Arc<Self>, interval: Duration, shutdown: CancellationToken) {
+        // The files last tried, a broken rotation is reported once and not retried until the
+        // files change again
+        let mut seen = read(&self.cert, &self.key).ok();
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => (),
+                _ = shutdown.cancelled() => return,
+            }
+
+            let files = match read(&self.cert, &self.key) {
+                Ok(files) => files,
+                Err(e) => {
+                    log::error!("Failed to read the TLS certificate: {:#}", e);
+                    continue;
+                }
+            };
+            if seen.as_ref() == Some(&files) {
+                continue;
+            }
+
+            match server_config(&files.0, &files.1) {
+                Ok(config) => {
+                    *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+                    log::info!("Reloaded the TLS certificate {}", self.cert.display());
+                }
+                Err(e) => log::error!(
+                    "Failed to reload the TLS certificate, keeping the previous one: {:#}",
+                    e
+                ),
+            }
+            seen = Some(files);
+        }
+    }
+}
+
+fn read(cert: &Path, key: &Path) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let cert_pem =
+        std::fs::read(cert).with_context(|| format!("could not read {}", cert.display()))?;
+    let key_pem =
+        std::fs::read(key).with_context(|| format!("could not read {}", key.display()))?;
+
+    Ok((cert_pem, key_pem))
+}
+
+fn server_config(cert_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<ServerConfig> {
+    let chain = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .context("invalid certificate")?;
+    if chain.is_empty() {
+        anyhow::bail!("the certificate file contains no certificate");
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem).context("invalid private key")?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .context("the private key does not match the certificate")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Accepts TCP connections and completes their TLS handshakes concurrently, so a slow client
+/// does not hold up the others
+pub struct TlsListener {
+    tcp: TcpListener,
+    certificate: Arc<Certificate>,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+impl TlsListener {
+    pub fn new(tcp: TcpListener, certificate: Arc<Certificate>) -> Self {
+        Self {
+            tcp,
+            certificate,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                (stream, addr) = Listener::accept(&mut self.tcp) => {
+                    let acceptor = TlsAcceptor::from(self.certificate.current());
+                    self.handshakes.push(Box::pin(async move {
+                        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                            .await
+                            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+                        (stream, addr)
+                    }));
+                }
+                Some((stream, addr)) = self.handshakes.next(), if !self.handshakes.is_empty() => {
+                    match stream {
+                        Ok(stream) => return (stream, addr),
+                        Err(e) => log::debug!("TLS handshake with {} failed: {}", addr, e),
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.tcp.local_addr()
+    }
+}
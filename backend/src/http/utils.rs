@@ -1,53 +1,16 @@
-use std::sync::Arc;
+use std::fmt;
 
+use crate::crud::email_outbox;
+use crate::crud::email_token::{self, Purpose};
 use crate::http::{error::Error as HTTPError, AppState};
+use crate::mailer::Email;
+use crate::templates::Template;
 use anyhow::Error;
-use mail_send::mail_builder::MessageBuilder;
+use minijinja::{context, Value};
 use rand::{distributions::Alphanumeric, Rng};
-
-const VERIFICATION_TEMPLATE: &str = r#"
-<!DOCTYPE html>
-<html>
-<head>
-    <meta charset='UTF-8'>
-    <meta name='viewport' content='width=device-width, initial-scale=1.0'>
-    <title>Email Verification</title>
-    <style>
-        body {
-            font-family: Arial, sans-serif;
-            background-color: #f4f4f4;
-            padding: 20px;
-        }
-        .container {
-            max-width: 600px;
-            margin: 0 auto;
-            background: #ffffff;
-            padding: 20px;
-            border-radius: 8px;
-            box-shadow: 0 0 10px rgba(0, 0, 0, 0.1);
-            text-align: center;
-        }
-        .button {
-            display: inline-block;
-            background-color: #007bff;
-            color: #ffffff;
-            padding: 10px 20px;
-            text-decoration: none;
-            border-radius: 5px;
-            font-weight: bold;
-        }
-    </style>
-</head>
-<body>
-    <div class='container'>
-        <h2>Email Verification</h2>
-        <p>Click the button below to verify your email address:</p>
-        <a href='{{verification_link}}' class='button'>Verify Email</a>
-        <p>If you did not request this, you can safely ignore this email.</p>
-    </div>
-</body>
-</html>
-"#;
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+use uuid::Uuid;
 
 pub fn random_string(length: usize) -> String {
     rand::thread_rng()
@@ -57,34 +20,223 @@ pub fn random_string(length: usize) -> String {
         .collect()
 }
 
-pub async fn send_verification(
-    to: String,
-    username: String,
-    token: String,
-    state: Arc<AppState>,
-) -> Result<(), HTTPError> {
-    let verification_link = format!("http://localhost:8080/auth-user/{}/{}", username, token);
-    let body = VERIFICATION_TEMPLATE.replace("{{verification_link}}", &verification_link);
+/// Hash a single-use token before it is stored, so a leaked table does not leak usable tokens.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Token sent by email, formatted as `<id>.<secret>`. The id is used to find the stored token,
+/// the secret (~256 bits) is only stored hashed and compared in constant time.
+pub struct EmailToken {
+    pub id: Uuid,
+    pub secret: String,
+}
+
+impl EmailToken {
+    pub fn generate() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            secret: random_string(43),
+        }
+    }
 
-    send_mail(&to, "Email Verification", &body, &state).await?;
+    pub fn parse(token: &str) -> Option<Self> {
+        let (id, secret) = token.split_once('.')?;
+        Some(Self {
+            id: Uuid::try_parse(id).ok()?,
+            secret: secret.to_owned(),
+        })
+    }
 
-    Ok(())
+    pub fn hash(&self) -> String {
+        hash_token(&self.secret)
+    }
+}
+
+impl fmt::Display for EmailToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.id.simple(), self.secret)
+    }
+}
+
+/// Issue a new email token, outstanding tokens of the user for the same purpose stop working
+pub async fn issue_email_token(
+    user_id: &Uuid,
+    purpose: Purpose,
+    db: &mut PgConnection,
+) -> Result<EmailToken, HTTPError> {
+    let token = EmailToken::generate();
+
+    email_token::invalidate_tokens(user_id, purpose, &mut *db).await?;
+    email_token::create_token(&token.id, user_id, purpose, &token.hash(), None, db).await?;
+
+    Ok(token)
 }
 
-pub async fn send_mail(to: &str, subject: &str, html: &str, state: &AppState) -> Result<(), Error> {
-    // send mail
-    let mut smtp_client = state.smtp_pool.get().await?;
+/// Issue the tokens for an email change, one confirming the new address and one cancelling the
+/// change from the old address. A previously requested change stops working.
+pub async fn issue_email_change(
+    user_id: &Uuid,
+    new_email: &str,
+    db: &mut PgConnection,
+) -> Result<(EmailToken, EmailToken), HTTPError> {
+    let confirm = EmailToken::generate();
 
-    let message = MessageBuilder::new()
-        .from((
-            state.config.mail_sender.to_string(),
-            state.config.mail_from.to_string(),
-        ))
-        .to(to)
-        .subject(subject)
-        .html_body(html);
+    email_token::invalidate_tokens(user_id, Purpose::ChangeEmail, &mut *db).await?;
+    email_token::create_token(
+        &confirm.id,
+        user_id,
+        Purpose::ChangeEmail,
+        &confirm.hash(),
+        Some(new_email),
+        &mut *db,
+    )
+    .await?;
+    let cancel = issue_email_token(user_id, Purpose::CancelEmailChange, db).await?;
 
-    smtp_client.send(message).await?;
+    Ok((confirm, cancel))
+}
+
+// Emails are rendered in the locale of the recipient and queued in the outbox with the
+// connection or transaction of the change they are about, the outbox worker delivers them with
+// `send_mail`.
+
+async fn queue(
+    to: &str,
+    template: Template,
+    locale: Option<&str>,
+    values: Value,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let email = state.templates.render(template, locale, values)?;
+    email_outbox::enqueue(to, &email.subject, &email.html, &email.text, db).await?;
 
     Ok(())
 }
+
+pub async fn queue_verification(
+    to: &str,
+    locale: Option<&str>,
+    token: &str,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! { verification_link => state.links.verify_email(token) };
+
+    queue(to, Template::Verification, locale, values, state, db).await
+}
+
+pub async fn queue_password_reset(
+    to: &str,
+    locale: Option<&str>,
+    token: &str,
+    expires_in: time::Duration,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! {
+        reset_link => state.links.reset_password(token),
+        expires_in_minutes => expires_in.whole_minutes(),
+    };
+
+    queue(to, Template::PasswordReset, locale, values, state, db).await
+}
+
+pub async fn queue_email_change(
+    to: &str,
+    locale: Option<&str>,
+    token: &str,
+    expires_in: time::Duration,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! {
+        confirm_link => state.links.confirm_email_change(token),
+        expires_in_minutes => expires_in.whole_minutes(),
+    };
+
+    queue(to, Template::EmailChange, locale, values, state, db).await
+}
+
+pub async fn queue_email_change_notice(
+    to: &str,
+    locale: Option<&str>,
+    new_email: &str,
+    token: &str,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! {
+        new_email,
+        cancel_link => state.links.cancel_email_change(token),
+    };
+
+    queue(to, Template::EmailChangeNotice, locale, values, state, db).await
+}
+
+pub async fn queue_account_locked(
+    to: &str,
+    locale: Option<&str>,
+    locked_for: time::Duration,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! { locked_for_minutes => locked_for.whole_minutes() };
+
+    queue(to, Template::AccountLocked, locale, values, state, db).await
+}
+
+pub async fn queue_registration_attempt(
+    to: &str,
+    locale: Option<&str>,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! { login_link => state.links.login() };
+
+    queue(to, Template::RegistrationAttempt, locale, values, state, db).await
+}
+
+pub async fn queue_username_taken(
+    to: &str,
+    locale: Option<&str>,
+    username: &str,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! { username, register_link => state.links.register() };
+
+    queue(to, Template::UsernameTaken, locale, values, state, db).await
+}
+
+pub async fn queue_welcome(
+    to: &str,
+    locale: Option<&str>,
+    username: &str,
+    state: &AppState,
+    db: &mut PgConnection,
+) -> Result<(), HTTPError> {
+    let values = context! { username, login_link => state.links.login() };
+
+    queue(to, Template::Welcome, locale, values, state, db).await
+}
+
+pub async fn send_mail(
+    to: &str,
+    subject: &str,
+    html: &str,
+    text: &str,
+    state: &AppState,
+) -> Result<(), Error> {
+    let email = Email {
+        from_name: state.config.mail_sender.to_string(),
+        from_address: state.config.mail_from.to_string(),
+        to: to.to_string(),
+        subject: subject.to_string(),
+        html: html.to_string(),
+        text: text.to_string(),
+    };
+
+    state.mailer.send(&email).await
+}
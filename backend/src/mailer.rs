@@ -0,0 +1,404 @@
+// Mail transports behind one trait, picked with `--mail-transport`
+//
+// Production sends over SMTP. For local development emails can go to a catcher like MailHog
+// over plain SMTP, to `.eml` files or to stdout. Tests build a `MemoryMailer` themselves, so
+// they keep a handle to read the captured emails back.
+use crate::config::Config;
+use deadpool::{
+    Runtime, Status,
+    managed::{Manager, Metrics, Object, Pool, RecycleError, RecycleResult},
+};
+use futures::future::BoxFuture;
+use mail_send::{SmtpClient, SmtpClientBuilder, mail_builder::MessageBuilder};
+use serde::Serialize;
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use uuid::Uuid;
+
+// Connections kept open to the SMTP server
+const SMTP_POOL_SIZE: usize = 10;
+
+// A healthy server answers RSET at once, a hung one must not stall every send
+const SMTP_RECYCLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Deliver to an SMTP server
+    Smtp,
+    /// Write every email as an .eml file to `--mail-dir`
+    File,
+    /// Print every email to stdout
+    Stdout,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SmtpSecurity {
+    /// TLS from the first byte, usually port 465
+    Tls,
+    /// Upgrade a plain connection with STARTTLS, usually port 587
+    Starttls,
+    /// No encryption, only for local mail catchers
+    Plain,
+}
+
+impl SmtpSecurity {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Self::Tls => 465,
+            Self::Starttls => 587,
+            Self::Plain => 25,
+        }
+    }
+}
+
+/// A rendered email ready for delivery
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub from_name: String,
+    pub from_address: String,
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    /// Plain text alternative, empty if there is none
+    pub text: String,
+}
+
+impl Email {
+    fn message(&self) -> MessageBuilder<'_> {
+        let message = MessageBuilder::new()
+            .from((self.from_name.as_str(), self.from_address.as_str()))
+            .to(self.to.as_str())
+            .subject(self.subject.as_str())
+            .html_body(self.html.as_str());
+        match self.text.is_empty() {
+            true => message,
+            false => message.text_body(self.text.as_str()),
+        }
+    }
+}
+
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Statistics of the connection pool, for transports that keep connections
+    fn pool_status(&self) -> Option<Status> {
+        None
+    }
+
+    /// Close open connections on shutdown, later sends fail
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    /// Check that emails can be handed over, for the readiness probe
+    fn check(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The mailer selected in the config
+pub fn from_config(config: &Config) -> anyhow::Result<Arc<dyn Mailer>> {
+    let mailer: Arc<dyn Mailer> = match config.mail_transport {
+        Transport::Smtp => {
+            let manager = SmtpManager {
+                host: config.mail_host.clone(),
+                port: config.mail_port(),
+                security: config.mail_security,
+                username: config.mail_username.clone(),
+                password: config.mail_password.clone(),
+                max_age: Duration::from_secs(config.mail_connection_max_age),
+                idle_timeout: Duration::from_secs(config.mail_connection_idle_timeout),
+            };
+            let pool = Pool::builder(manager)
+                .max_size(SMTP_POOL_SIZE)
+                .recycle_timeout(Some(SMTP_RECYCLE_TIMEOUT))
+                .runtime(Runtime::Tokio1)
+                .build()?;
+            Arc::new(SmtpMailer { pool })
+        }
+        Transport::File => {
+            std::fs::create_dir_all(&config.mail_dir)?;
+            Arc::new(FileMailer {
+                dir: config.mail_dir.clone(),
+            })
+        }
+        Transport::Stdout => Arc::new(StdoutMailer),
+    };
+
+    Ok(mailer)
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpManager {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Connections older than this are closed instead of reused
+    pub max_age: Duration,
+    /// Connections unused for longer than this are closed, servers drop idle clients on their own
+    pub idle_timeout: Duration,
+}
+
+/// A connection of the SMTP pool, encrypted unless the security is `Plain`
+pub enum SmtpConnection {
+    Tls(Box<SmtpClient<TlsStream<TcpStream>>>),
+    Plain(SmtpClient<TcpStream>),
+}
+
+impl SmtpConnection {
+    async fn send(&mut self, email: &Email) -> Result<(), mail_send::Error> {
+        match self {
+            Self::Tls(client) => client.send(email.message()).await,
+            Self::Plain(client) => client.send(email.message()).await,
+        }
+    }
+
+    async fn rset(&mut self) -> Result<(), mail_send::Error> {
+        match self {
+            Self::Tls(client) => client.rset().await,
+            Self::Plain(client) => client.rset().await,
+        }
+    }
+
+    async fn quit(self) -> Result<(), mail_send::Error> {
+        match self {
+            Self::Tls(client) => client.quit().await,
+            Self::Plain(client) => client.quit().await,
+        }
+    }
+}
+
+impl Manager for SmtpManager {
+    type Type = SmtpConnection;
+    type Error = mail_send::Error;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        let mut builder = SmtpClientBuilder::new(self.host.clone(), self.port)
+            .implicit_tls(self.security == SmtpSecurity::Tls);
+        // Empty values count as unset, e.g. `MAIL_USERNAME=` in a compose file
+        if let (Some(username), Some(password)) = (&self.username, &self.password)
+            && !username.is_empty()
+            && !password.is_empty()
+        {
+            builder = builder.credentials((username.clone(), password.clone()));
+        }
+
+        match self.security {
+            SmtpSecurity::Tls | SmtpSecurity::Starttls => builder
+                .connect()
+                .await
+                .map(|client| SmtpConnection::Tls(Box::new(client))),
+            SmtpSecurity::Plain => builder.connect_plain().await.map(SmtpConnection::Plain),
+        }
+    }
+
+    async fn recycle(
+        &self,
+        connection: &mut Self::Type,
+        metrics: &Metrics,
+    ) -> RecycleResult<Self::Error> {
+        if metrics.age() > self.max_age {
+            return Err(RecycleError::message("connection reached its maximum age"));
+        }
+        if metrics.last_used() > self.idle_timeout {
+            return Err(RecycleError::message("connection was idle for too long"));
+        }
+
+        // RSET proves the server still answers and aborts a transaction a failed send left open
+        connection.rset().await.map_err(RecycleError::Backend)
+    }
+}
+
+pub struct SmtpMailer {
+    pool: Pool<SmtpManager>,
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut connection = self.pool.get().await?;
+            let reused = Object::metrics(&connection).recycle_count > 0;
+
+            match connection.send(email).await {
+                Ok(()) => Ok(()),
+                // The server may have closed a pooled connection since it was checked
+                Err(e) if reused && is_connection_error(&e) => {
+                    log::warn!(
+                        "Pooled SMTP connection failed, retrying on a new one: {}",
+                        e
+                    );
+                    drop(Object::take(connection));
+                    let mut connection = self.pool.manager().create().await?;
+                    connection.send(email).await?;
+                    Ok(())
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn pool_status(&self) -> Option<Status> {
+        Some(self.pool.status())
+    }
+
+    fn check(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            // An idle connection is recycled with RSET, else a new one connects and logs in
+            drop(self.pool.get().await?);
+            Ok(())
+        })
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            // Connections in use are dropped when they are returned
+            let idle = self.pool.retain(|_, _| false).removed;
+            self.pool.close();
+            // QUIT lets the server end the session instead of seeing the connection drop
+            let results =
+                futures::future::join_all(idle.into_iter().map(SmtpConnection::quit)).await;
+            let failed = results.iter().filter(|result| result.is_err()).count();
+            if failed > 0 {
+                log::debug!("{} SMTP connections did not close cleanly", failed);
+            }
+        })
+    }
+}
+
+// Errors after which the message may well go through on another connection, unlike a
+// rejected recipient or failed authentication
+fn is_connection_error(error: &mail_send::Error) -> bool {
+    match error {
+        mail_send::Error::Io(_)
+        | mail_send::Error::Timeout
+        | mail_send::Error::UnparseableReply => true,
+        // 421: the server is closing the connection
+        mail_send::Error::UnexpectedReply(response) => response.code == 421,
+        _ => false,
+    }
+}
+
+/// Writes every email to its own `<unix millis>-<uuid>.eml` file
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let millis = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+            let path = self.dir.join(format!("{}-{}.eml", millis, Uuid::new_v4()));
+            tokio::fs::write(&path, email.message().write_to_vec()?).await?;
+            log::debug!("Wrote email to {}", path.display());
+            Ok(())
+        })
+    }
+}
+
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            // The text body, followed by the HTML one when the email has it
+            let mut body = email.text.clone();
+            if !email.html.is_empty() {
+                body.push_str("\n\n----- html -----\n");
+                body.push_str(&email.html);
+            }
+            // One write under the lock, so concurrent emails do not interleave
+            let mut stdout = std::io::stdout().lock();
+            writeln!(
+                stdout,
+                "----- email -----\nFrom: {} <{}>\nTo: {}\nSubject: {}\n\n{}\n-----------------",
+                email.from_name, email.from_address, email.to, email.subject, body
+            )?;
+            Ok(())
+        })
+    }
+}
+
+/// Captures emails for tests to inspect
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+    failing: AtomicBool,
+}
+
+impl MemoryMailer {
+    /// Every email sent so far
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Every email sent so far, forgetting them
+    pub fn take(&self) -> Vec<Email> {
+        std::mem::take(&mut *self.sent.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Make sending fail, e.g. to exercise the retries of the outbox
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Relaxed);
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            if self.failing.load(Ordering::Relaxed) {
+                anyhow::bail!("memory mailer is set to fail");
+            }
+            self.sent
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(email.clone());
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str) -> Email {
+        Email {
+            from_name: "Example".to_owned(),
+            from_address: "noreply@example.com".to_owned(),
+            to: to.to_owned(),
+            subject: "Subject".to_owned(),
+            html: "<p>Body</p>".to_owned(),
+            text: "Body".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_mailer_captures_emails() {
+        let memory = Arc::new(MemoryMailer::default());
+        let mailer: Arc<dyn Mailer> = memory.clone();
+
+        mailer.send(&email("a@example.com")).await.unwrap();
+        mailer.send(&email("b@example.com")).await.unwrap();
+        assert_eq!(memory.sent().len(), 2);
+
+        let taken = memory.take();
+        assert_eq!(taken, vec![email("a@example.com"), email("b@example.com")]);
+        assert!(memory.sent().is_empty());
+
+        memory.set_failing(true);
+        assert!(mailer.send(&email("c@example.com")).await.is_err());
+        assert!(memory.sent().is_empty());
+    }
+}
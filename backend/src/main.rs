@@ -1,8 +1,7 @@
 use anyhow::Context; // Needed for context to work
 use clap::Parser; // Needed for parse to work
-use deadpool::managed::Pool;
 use rust_backend::http;
-use rust_backend::{SmtpManager, config::Config};
+use rust_backend::{config::Config, crud, mailer};
 use sqlx::postgres::PgPoolOptions;
 
 #[tokio::main]
@@ -19,16 +18,10 @@ async fn main() -> anyhow::Result<()> {
 
     // Load config
     let config = Config::parse();
+    config.validate().context("invalid configuration")?;
 
-    // Create SMTP pool
-    let smtp_manager = SmtpManager {
-        host: config.mail_host.clone(),
-        port: config.mail_port,
-        username: config.mail_username.clone(),
-        password: config.mail_password.clone(),
-    };
-
-    let smtp_pool: Pool<SmtpManager> = Pool::builder(smtp_manager).max_size(10).build().unwrap();
+    // Create the mail transport
+    let mailer = mailer::from_config(&config).context("could not set up the mail transport")?;
 
     // Create DB pool
     let db = PgPoolOptions::new()
@@ -38,13 +31,13 @@ async fn main() -> anyhow::Result<()> {
         .context("could not connect to database_url")?;
 
     // Migrate the DB
-    sqlx::migrate!()
+    crud::MIGRATOR
         .run(&db)
         .await
         .context("could not run migrations")?;
 
     // Start Server
-    http::serve(config, db, smtp_pool).await.unwrap();
+    http::serve(config, db, mailer).await?;
 
     Ok(())
 }
@@ -0,0 +1,303 @@
+// Password hashing off the async runtime
+//
+// Argon2 is deliberately slow and memory hungry. Running it on a Tokio worker stalls every other
+// request scheduled on that worker, so hashes are computed on the blocking pool, and a semaphore
+// bounds how many run at once so a burst of logins cannot exhaust memory or the pool.
+//
+// New hashes are always argon2id with the configured parameters. Hashes in older formats or
+// with other parameters still verify, the caller is told to replace them.
+
+use argon2::{
+    Algorithm, Argon2, Params, Version,
+    password_hash::{
+        self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng,
+    },
+};
+use pbkdf2::Pbkdf2;
+use scrypt::Scrypt;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid password hash: {0}")]
+    Hash(password_hash::Error),
+
+    #[error("invalid argon2 parameters: {0}")]
+    Params(argon2::Error),
+
+    #[error("invalid bcrypt hash: {0}")]
+    Bcrypt(bcrypt::BcryptError),
+
+    #[error("hashing task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+// Without its `std` feature the error does not implement `std::error::Error`, so no `#[from]`
+impl From<password_hash::Error> for Error {
+    fn from(e: password_hash::Error) -> Self {
+        Self::Hash(e)
+    }
+}
+
+pub struct Hasher {
+    params: Params,
+    permits: Arc<Semaphore>,
+    dummy_hash: String,
+}
+
+impl Hasher {
+    /// `memory_kib`, `iterations` and `parallelism` apply to new hashes, existing hashes are
+    /// verified with the parameters stored in them
+    pub fn new(
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+        concurrency: usize,
+    ) -> Result<Self, Error> {
+        let params =
+            Params::new(memory_kib, iterations, parallelism, None).map_err(Error::Params)?;
+        // A fixed salt and an all-zero output, no password is known to produce it
+        let dummy_hash = format!(
+            "$argon2id$v=19$m={},t={},p={}$c29tZXNhbHRzb21lc2FsdA${}",
+            memory_kib,
+            iterations,
+            parallelism,
+            "A".repeat(43)
+        );
+
+        Ok(Self {
+            params,
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            dummy_hash,
+        })
+    }
+
+    /// A hash with the current parameters that matches no password. Verifying against it when
+    /// there is no stored hash, e.g. for an unknown user, takes as long as a wrong password.
+    pub fn dummy_hash(&self) -> &str {
+        &self.dummy_hash
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Hash a new password into a PHC string
+    pub async fn hash(&self, password: String) -> Result<String, Error> {
+        let argon2 = self.argon2();
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("never closed");
+
+        // The permit moves into the task, it is held until the hash is done even if the caller
+        // stops waiting for it
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let salt = SaltString::generate(&mut OsRng);
+            Ok(argon2
+                .hash_password(password.as_bytes(), &salt)?
+                .to_string())
+        })
+        .await?
+    }
+
+    /// Check a password against a stored hash. Argon2, scrypt and PBKDF2 PHC strings and
+    /// bcrypt hashes are accepted, anything but an argon2id hash with the current parameters is
+    /// reported as outdated.
+    pub async fn verify(
+        &self,
+        password: String,
+        password_hash: String,
+    ) -> Result<Verification, Error> {
+        let params = self.params.clone();
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("never closed");
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            verify_blocking(&password, &password_hash, &params)
+        })
+        .await?
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    Mismatch,
+    /// `outdated` hashes should be replaced by a new hash of the same password
+    Match {
+        outdated: bool,
+    },
+}
+
+fn verify_blocking(
+    password: &str,
+    password_hash: &str,
+    current: &Params,
+) -> Result<Verification, Error> {
+    // bcrypt predates PHC strings and has its own format
+    if ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| password_hash.starts_with(prefix))
+    {
+        return match bcrypt::verify(password, password_hash) {
+            Ok(true) => Ok(Verification::Match { outdated: true }),
+            Ok(false) => Ok(Verification::Mismatch),
+            Err(e) => Err(Error::Bcrypt(e)),
+        };
+    }
+
+    let parsed_hash = PasswordHash::new(password_hash)?;
+    let verifiers: [&dyn PasswordVerifier; 3] = [&Argon2::default(), &Scrypt, &Pbkdf2];
+    match parsed_hash.verify_password(&verifiers, password) {
+        Ok(()) => Ok(Verification::Match {
+            outdated: !is_current(&parsed_hash, current),
+        }),
+        Err(password_hash::Error::Password) => Ok(Verification::Mismatch),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Whether a hash is what `Hasher::hash` would produce today
+fn is_current(parsed_hash: &PasswordHash, current: &Params) -> bool {
+    let Ok(params) = Params::try_from(parsed_hash) else {
+        return false;
+    };
+
+    parsed_hash.algorithm == Algorithm::Argon2id.ident()
+        && parsed_hash.version == Some(Version::V0x13.into())
+        && params.m_cost() == current.m_cost()
+        && params.t_cost() == current.t_cost()
+        && params.p_cost() == current.p_cost()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "Sunny-Meadow42";
+
+    // Cheap parameters, the tests are about formats and not about strength
+    fn current() -> Params {
+        Params::new(64, 1, 1, None).unwrap()
+    }
+
+    fn argon2(params: Params, password: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn legacy_hashes(password: &str) -> Vec<(&'static str, String)> {
+        let salt = SaltString::generate(&mut OsRng);
+        let bcrypt = bcrypt::hash_with_result(password, 4).unwrap();
+        vec![
+            (
+                "bcrypt $2a$",
+                bcrypt.format_for_version(bcrypt::Version::TwoA),
+            ),
+            (
+                "bcrypt $2b$",
+                bcrypt.format_for_version(bcrypt::Version::TwoB),
+            ),
+            (
+                "bcrypt $2y$",
+                bcrypt.format_for_version(bcrypt::Version::TwoY),
+            ),
+            (
+                "scrypt",
+                Scrypt
+                    .hash_password_customized(
+                        password.as_bytes(),
+                        None,
+                        None,
+                        scrypt::Params::new(4, 8, 1, 32).unwrap(),
+                        &salt,
+                    )
+                    .unwrap()
+                    .to_string(),
+            ),
+            (
+                "pbkdf2",
+                Pbkdf2
+                    .hash_password_customized(
+                        password.as_bytes(),
+                        None,
+                        None,
+                        pbkdf2::Params {
+                            rounds: 1000,
+                            output_length: 32,
+                        },
+                        &salt,
+                    )
+                    .unwrap()
+                    .to_string(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn legacy_hashes_verify_and_are_outdated() {
+        for (name, hash) in legacy_hashes(PASSWORD) {
+            assert_eq!(
+                verify_blocking(PASSWORD, &hash, &current()).unwrap(),
+                Verification::Match { outdated: true },
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn argon2_is_current_only_with_the_configured_params() {
+        let hash = argon2(current(), PASSWORD);
+        assert_eq!(
+            verify_blocking(PASSWORD, &hash, &current()).unwrap(),
+            Verification::Match { outdated: false }
+        );
+
+        let weaker = argon2(Params::new(32, 1, 1, None).unwrap(), PASSWORD);
+        assert_eq!(
+            verify_blocking(PASSWORD, &weaker, &current()).unwrap(),
+            Verification::Match { outdated: true }
+        );
+        let more_iterations = argon2(Params::new(64, 2, 1, None).unwrap(), PASSWORD);
+        assert_eq!(
+            verify_blocking(PASSWORD, &more_iterations, &current()).unwrap(),
+            Verification::Match { outdated: true }
+        );
+    }
+
+    #[test]
+    fn wrong_password_is_rejected_by_every_algorithm() {
+        let mut hashes = legacy_hashes(PASSWORD);
+        hashes.push(("argon2id", argon2(current(), PASSWORD)));
+        for (name, hash) in hashes {
+            assert_eq!(
+                verify_blocking("Rainy-Valley77", &hash, &current()).unwrap(),
+                Verification::Mismatch,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn dummy_hash_matches_nothing() {
+        let hasher = Hasher::new(64, 1, 1, 1).unwrap();
+        assert_eq!(
+            verify_blocking("", hasher.dummy_hash(), &current()).unwrap(),
+            Verification::Mismatch
+        );
+    }
+}
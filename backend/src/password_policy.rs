@@ -0,0 +1,385 @@
+// Rules new passwords have to follow
+//
+// Every rule is checked, so a rejection lists all problems at once instead of one per attempt.
+// Breached passwords are looked up with the k-anonymity range API of Pwned Passwords: only the
+// first five hex digits of the SHA-1 hash leave the server.
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::{collections::HashSet, path::Path, sync::Arc, time::Duration};
+
+const BUILTIN_COMMON_PASSWORDS: &str = include_str!("../data/common-passwords.txt");
+
+// Parts of the username or email shorter than this are not worth rejecting
+const MIN_IDENTITY_LENGTH: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum CharClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Lowercase => c.is_lowercase(),
+            Self::Uppercase => c.is_uppercase(),
+            Self::Digit => c.is_numeric(),
+            Self::Symbol => !c.is_alphanumeric() && !c.is_whitespace(),
+        }
+    }
+}
+
+/// A rule a password breaks, serialized as `{"rule": "too_short", "min": 8, "message": ...}`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Violation {
+    TooShort { min: usize, message: String },
+    TooLong { max: usize, message: String },
+    MissingCharClass { class: CharClass, message: String },
+    ContainsUsername { message: String },
+    ContainsEmail { message: String },
+    Common { message: String },
+    Breached { message: String },
+}
+
+/// Looks up breached passwords by the first five hex digits of their SHA-1 hash
+pub trait BreachChecker: Send + Sync {
+    /// The remaining 35 uppercase hex digits of every breached hash with that prefix
+    fn suffixes<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>>;
+}
+
+/// The range API of Pwned Passwords
+pub struct PwnedPasswords {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl PwnedPasswords {
+    /// `url` is the range endpoint the prefix is appended to
+    pub fn new(url: String) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(3))
+            .build()?;
+        Ok(Self { client, url })
+    }
+}
+
+impl BreachChecker for PwnedPasswords {
+    fn suffixes<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        Box::pin(async move {
+            let body = self
+                .client
+                .get(format!("{}{}", self.url, prefix))
+                // Padded responses hide the number of matches from anyone watching the traffic
+                .header("Add-Padding", "true")
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+
+            // Lines are `SUFFIX:COUNT`, padding entries have a count of 0
+            Ok(body
+                .lines()
+                .filter_map(|line| line.trim().split_once(':'))
+                .filter(|(_, count)| *count != "0")
+                .map(|(suffix, _)| suffix.to_owned())
+                .collect())
+        })
+    }
+}
+
+/// A fixed set of breached passwords, a stand-in for the API in tests and offline setups
+pub struct LocalBreachChecker {
+    hashes: HashSet<String>,
+}
+
+impl LocalBreachChecker {
+    pub fn new<'a>(passwords: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            hashes: passwords.into_iter().map(sha1_hex).collect(),
+        }
+    }
+}
+
+impl BreachChecker for LocalBreachChecker {
+    fn suffixes<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        Box::pin(async move {
+            Ok(self
+                .hashes
+                .iter()
+                .filter_map(|hash| hash.strip_prefix(prefix))
+                .map(str::to_owned)
+                .collect())
+        })
+    }
+}
+
+fn sha1_hex(password: &str) -> String {
+    hex::encode_upper(Sha1::digest(password.as_bytes()))
+}
+
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub required_classes: Vec<CharClass>,
+    common: HashSet<String>,
+    breach_checker: Option<Arc<dyn BreachChecker>>,
+}
+
+impl PasswordPolicy {
+    /// `common_passwords_file` replaces the built-in list, one password per line
+    pub fn new(
+        min_length: usize,
+        max_length: usize,
+        required_classes: Vec<CharClass>,
+        common_passwords_file: Option<&Path>,
+        breach_checker: Option<Arc<dyn BreachChecker>>,
+    ) -> anyhow::Result<Self> {
+        let common = match common_passwords_file {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => BUILTIN_COMMON_PASSWORDS.to_owned(),
+        };
+        let common = common
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+
+        Ok(Self {
+            min_length,
+            max_length,
+            required_classes,
+            common,
+            breach_checker,
+        })
+    }
+
+    /// Every rule the password breaks, empty if it is acceptable
+    pub async fn check(&self, password: &str, username: &str, email: &str) -> Vec<Violation> {
+        let mut violations = self.check_local(password, username, email);
+
+        // Known to be bad already, no need to ask the breach checker
+        if violations.is_empty()
+            && let Some(checker) = &self.breach_checker
+        {
+            let hash = sha1_hex(password);
+            let (prefix, suffix) = hash.split_at(5);
+            match checker.suffixes(prefix).await {
+                Ok(suffixes) if suffixes.iter().any(|s| s.eq_ignore_ascii_case(suffix)) => {
+                    violations.push(Violation::Breached {
+                        message: "appeared in a known data breach".to_owned(),
+                    });
+                }
+                Ok(_) => (),
+                // An unreachable breach API must not keep users from signing up
+                Err(e) => log::warn!("Breached password check failed: {:?}", e),
+            }
+        }
+
+        violations
+    }
+
+    fn check_local(&self, password: &str, username: &str, email: &str) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(Violation::TooShort {
+                min: self.min_length,
+                message: format!("must be at least {} characters long", self.min_length),
+            });
+        }
+        if length > self.max_length {
+            violations.push(Violation::TooLong {
+                max: self.max_length,
+                message: format!("must be at most {} characters long", self.max_length),
+            });
+        }
+
+        for class in &self.required_classes {
+            if !password.chars().any(|c| class.matches(c)) {
+                let name = match class {
+                    CharClass::Lowercase => "a lowercase letter",
+                    CharClass::Uppercase => "an uppercase letter",
+                    CharClass::Digit => "a digit",
+                    CharClass::Symbol => "a symbol",
+                };
+                violations.push(Violation::MissingCharClass {
+                    class: *class,
+                    message: format!("must contain {}", name),
+                });
+            }
+        }
+
+        let lowercase = password.to_lowercase();
+        let username = username.trim().to_lowercase();
+        if username.chars().count() >= MIN_IDENTITY_LENGTH && lowercase.contains(&username) {
+            violations.push(Violation::ContainsUsername {
+                message: "must not contain the username".to_owned(),
+            });
+        }
+
+        // The whole address and the part before the @ are both easy to guess
+        let email = email.trim().to_lowercase();
+        let local_part = email.split('@').next().unwrap_or_default();
+        if local_part.chars().count() >= MIN_IDENTITY_LENGTH && lowercase.contains(local_part) {
+            violations.push(Violation::ContainsEmail {
+                message: "must not contain the email address".to_owned(),
+            });
+        }
+
+        if self.common.contains(&lowercase) {
+            violations.push(Violation::Common {
+                message: "is too common".to_owned(),
+            });
+        }
+
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(breached: &[&str]) -> PasswordPolicy {
+        let checker: Arc<dyn BreachChecker> =
+            Arc::new(LocalBreachChecker::new(breached.iter().copied()));
+        PasswordPolicy::new(
+            8,
+            16,
+            vec![
+                CharClass::Lowercase,
+                CharClass::Uppercase,
+                CharClass::Digit,
+                CharClass::Symbol,
+            ],
+            None,
+            Some(checker),
+        )
+        .unwrap()
+    }
+
+    fn rules(violations: &[Violation]) -> Vec<String> {
+        violations
+            .iter()
+            .map(|v| {
+                serde_json::to_value(v).unwrap()["rule"]
+                    .as_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    async fn check(password: &str) -> Vec<String> {
+        rules(
+            &policy(&[])
+                .check(password, "alice", "alice@example.com")
+                .await,
+        )
+    }
+
+    #[tokio::test]
+    async fn acceptable_password_passes() {
+        assert!(check("Sunny-Meadow42").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn length_is_bounded() {
+        assert_eq!(check("Ab1-").await, ["too_short"]);
+        assert_eq!(check("Sunny-Meadow42-Sunny").await, ["too_long"]);
+
+        let violations = policy(&[])
+            .check("Ab1-", "alice", "alice@example.com")
+            .await;
+        assert!(matches!(violations[0], Violation::TooShort { min: 8, .. }));
+    }
+
+    #[tokio::test]
+    async fn every_missing_class_is_reported() {
+        let violations = policy(&[])
+            .check("........", "alice", "alice@example.com")
+            .await;
+        let classes: Vec<CharClass> = violations
+            .iter()
+            .filter_map(|v| match v {
+                Violation::MissingCharClass { class, .. } => Some(*class),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            classes,
+            [CharClass::Lowercase, CharClass::Uppercase, CharClass::Digit]
+        );
+        assert_eq!(check("sunny-meadow42").await, ["missing_char_class"]);
+        assert_eq!(check("SunnyMeadow42").await, ["missing_char_class"]);
+    }
+
+    #[tokio::test]
+    async fn violations_are_reported_together() {
+        assert_eq!(
+            check("alice").await,
+            [
+                "too_short",
+                "missing_char_class",
+                "missing_char_class",
+                "missing_char_class",
+                "contains_username",
+                "contains_email",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn identity_is_rejected() {
+        let policy = policy(&[]);
+        let check = |password: &'static str, username, email| {
+            let policy = &policy;
+            async move { rules(&policy.check(password, username, email).await) }
+        };
+
+        assert_eq!(
+            check("My-Bob-Pass42", "bob", "robert@example.com").await,
+            ["contains_username"]
+        );
+        assert_eq!(
+            check("Robert-Pass42", "bobby", "robert@example.com").await,
+            ["contains_email"]
+        );
+        // Too short to be worth rejecting
+        assert!(
+            check("Al-Sunny-42", "al", "al@example.com")
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn common_password_is_rejected() {
+        let policy = PasswordPolicy::new(8, 128, Vec::new(), None, None).unwrap();
+        let violations = policy.check("Password", "alice", "alice@example.com").await;
+        assert_eq!(rules(&violations), ["common"]);
+    }
+
+    #[tokio::test]
+    async fn breached_password_is_rejected() {
+        let policy = policy(&["Breached-Pass1"]);
+        let violations = policy
+            .check("Breached-Pass1", "alice", "alice@example.com")
+            .await;
+        assert_eq!(rules(&violations), ["breached"]);
+
+        let violations = policy
+            .check("Sunny-Meadow42", "alice", "alice@example.com")
+            .await;
+        assert!(violations.is_empty());
+    }
+}
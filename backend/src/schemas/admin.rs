@@ -0,0 +1,121 @@
+use crate::mailer::Transport;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUser {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub verified: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub suspended_at: Option<OffsetDateTime>,
+    pub suspension_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUserDetail {
+    #[serde(flatten)]
+    pub user: AdminUser,
+    pub roles: Vec<String>,
+    pub active_sessions: i64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub locked_until: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserFilter {
+    pub verified: Option<bool>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub created_after: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub created_before: Option<OffsetDateTime>,
+    // Matches the beginning of the username or the email, case insensitive
+    pub prefix: Option<String>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserPage {
+    pub users: Vec<AdminUser>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SuspendUser {
+    pub reason: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxStatus {
+    /// Waiting for its first or next delivery attempt
+    Pending,
+    /// Gave up after too many attempts, only delivered again once requeued
+    #[default]
+    Dead,
+}
+
+impl OutboxStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    pub id: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    pub status: OutboxStatus,
+    pub attempts: i32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub next_attempt_at: OffsetDateTime,
+    pub last_error: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxFilter {
+    // Defaults to the dead messages, the ones that need attention
+    #[serde(default)]
+    pub status: OutboxStatus,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxPage {
+    pub messages: Vec<OutboxMessage>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MailStatus {
+    pub transport: Transport,
+    /// Only for transports that keep connections open
+    pub pool: Option<MailPool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MailPool {
+    pub max_size: usize,
+    /// Open connections, in use or idle
+    pub size: usize,
+    /// Idle connections ready to send
+    pub available: usize,
+    /// Sends waiting for a connection
+    pub waiting: usize,
+}
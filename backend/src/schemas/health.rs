@@ -0,0 +1,34 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Readiness {
+    Ready,
+    NotReady,
+    /// Stays until the process exits, load balancers should route elsewhere
+    ShuttingDown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentStatus {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Component {
+    pub status: ComponentStatus,
+    pub latency_ms: f64,
+    /// Why the component is down, details are only logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub status: Readiness,
+    /// Every checked dependency by name, empty while shutting down
+    pub components: BTreeMap<&'static str, Component>,
+}
@@ -1 +1,7 @@
+pub mod admin;
+pub mod health;
+pub mod roles;
+pub mod sessions;
+pub mod two_factor;
 pub mod users;
+pub mod validation;
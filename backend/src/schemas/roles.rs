@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GrantRole {
+    pub role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserRoles {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
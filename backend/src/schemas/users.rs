@@ -1,3 +1,4 @@
+use super::validation::{self, Validate, ValidationErrors};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -6,6 +7,22 @@ pub struct NewUser {
     pub username: String,
     pub password: String,
     pub email: String,
+    /// Language of the emails, taken from Accept-Language if missing
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+impl Validate for NewUser {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("username", validation::username(&mut self.username));
+        errors.check("email", validation::email(&mut self.email));
+        errors.check("password", validation::password(&mut self.password));
+        if let Some(locale) = &mut self.locale {
+            errors.check("locale", validation::locale(locale));
+        }
+        errors.into_result()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,21 +31,32 @@ pub struct UserLogin {
     pub password: String,
 }
 
+impl Validate for UserLogin {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("username", validation::login_name(&mut self.username));
+        errors.check("password", validation::existing_password(&self.password));
+        errors.into_result()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
     pub email: String,
     pub verified: bool,
+    pub locale: Option<String>,
 }
 
-impl User {
-    pub fn default() -> Self {
+impl Default for User {
+    fn default() -> Self {
         User {
             id: Uuid::nil(),
             username: String::from(""),
             email: String::from(""),
             verified: false,
+            locale: None,
         }
     }
 }
@@ -38,3 +66,101 @@ pub struct UpdatePassword {
     pub old_password: String,
     pub new_password: String,
 }
+
+impl Validate for UpdatePassword {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            "old_password",
+            validation::existing_password(&self.old_password),
+        );
+        errors.check("new_password", validation::password(&mut self.new_password));
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeEmail {
+    pub password: String,
+    pub new_email: String,
+}
+
+impl Validate for ChangeEmail {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("password", validation::existing_password(&self.password));
+        errors.check("new_email", validation::email(&mut self.new_email));
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+impl Validate for ResendVerification {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("email", validation::email(&mut self.email));
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+impl Validate for ForgotPassword {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("email", validation::email(&mut self.email));
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub new_password: String,
+}
+
+impl Validate for ResetPassword {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("token", validation::required(&mut self.token));
+        errors.check("new_password", validation::password(&mut self.new_password));
+        errors.into_result()
+    }
+}
+
+/// The token of an email change link, posted by the frontend page the link opens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailChangeToken {
+    pub token: String,
+}
+
+impl Validate for EmailChangeToken {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("token", validation::required(&mut self.token));
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateLocale {
+    /// None goes back to the Accept-Language of each request
+    pub locale: Option<String>,
+}
+
+impl Validate for UpdateLocale {
+    fn validate(&mut self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(locale) = &mut self.locale {
+            errors.check("locale", validation::locale(locale));
+        }
+        errors.into_result()
+    }
+}
@@ -0,0 +1,403 @@
+// Checks on request bodies, run by the `ValidatedJson` extractor before a handler sees them
+//
+// Text is normalized to Unicode NFC first, so the same name typed on different systems is
+// stored and compared as the same string.
+
+use serde::Serialize;
+use unicode_normalization::UnicodeNormalization;
+
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+
+// RFC 5321 limits of a deliverable address
+const EMAIL_MAX_LENGTH: usize = 254;
+const EMAIL_LOCAL_MAX_LENGTH: usize = 64;
+const EMAIL_LABEL_MAX_LENGTH: usize = 63;
+
+// RFC 5646 recommends supporting tags of at least 35 characters
+const LOCALE_MAX_LENGTH: usize = 35;
+
+// Passwords are only capped to bound the hashing work, the password policy sets the real limits
+const PASSWORD_MAX_LENGTH: usize = 1024;
+
+/// A request body that can be normalized and checked field by field
+pub trait Validate {
+    /// Normalize the fields in place and report every invalid one
+    fn validate(&mut self) -> Result<(), ValidationErrors>;
+}
+
+/// Why a single value is invalid, e.g. `{"code": "too_short", "message": ...}`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Invalid {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Invalid {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    #[serde(flatten)]
+    pub invalid: Invalid,
+}
+
+/// Every invalid field of a request body
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct ValidationErrors(pub Vec<FieldError>);
+
+impl ValidationErrors {
+    /// Record the outcome of checking one field
+    pub fn check(&mut self, field: &'static str, result: Result<(), Invalid>) {
+        if let Err(invalid) = result {
+            self.0.push(FieldError { field, invalid });
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), Self> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+}
+
+/// NFC normalize a value in place
+pub fn normalize(value: &mut String) {
+    *value = value.nfc().collect();
+}
+
+// Trimmed and normalized, for identifiers where surrounding whitespace is never intended
+fn normalize_identifier(value: &mut String) {
+    let trimmed = value.trim();
+    if trimmed.len() != value.len() {
+        *value = trimmed.to_owned();
+    }
+    normalize(value);
+}
+
+/// A name for a new account: letters, digits, `_`, `.` and `-`, starting with a letter or digit
+pub fn username(value: &mut String) -> Result<(), Invalid> {
+    normalize_identifier(value);
+
+    let length = value.chars().count();
+    if length < USERNAME_MIN_LENGTH {
+        return Err(Invalid::new(
+            "too_short",
+            format!("must be at least {} characters long", USERNAME_MIN_LENGTH),
+        ));
+    }
+    if length > USERNAME_MAX_LENGTH {
+        return Err(Invalid::new(
+            "too_long",
+            format!("must be at most {} characters long", USERNAME_MAX_LENGTH),
+        ));
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        return Err(Invalid::new(
+            "invalid_characters",
+            "may only contain letters, digits, '_', '.' and '-'",
+        ));
+    }
+    if !value.starts_with(char::is_alphanumeric) {
+        return Err(Invalid::new(
+            "invalid_characters",
+            "must start with a letter or digit",
+        ));
+    }
+
+    Ok(())
+}
+
+/// A name given at login, accounts created before the username rules must still get in
+pub fn login_name(value: &mut String) -> Result<(), Invalid> {
+    normalize_identifier(value);
+
+    if value.is_empty() {
+        return Err(Invalid::new("required", "must not be empty"));
+    }
+    if value.chars().count() > EMAIL_MAX_LENGTH {
+        return Err(Invalid::new(
+            "too_long",
+            format!("must be at most {} characters long", EMAIL_MAX_LENGTH),
+        ));
+    }
+
+    Ok(())
+}
+
+/// An email address in the common `local@domain.tld` form of RFC 5322, without comments,
+/// quoted local parts or address literals. Non-ASCII letters are accepted as in RFC 6531.
+pub fn email(value: &mut String) -> Result<(), Invalid> {
+    normalize_identifier(value);
+
+    let syntax = || Invalid::new("invalid_email", "must be a valid email address");
+    if value.is_empty() {
+        return Err(Invalid::new("required", "must not be empty"));
+    }
+    if value.len() > EMAIL_MAX_LENGTH {
+        return Err(Invalid::new(
+            "too_long",
+            format!("must be at most {} characters long", EMAIL_MAX_LENGTH),
+        ));
+    }
+
+    let (local, domain) = value.rsplit_once('@').ok_or_else(syntax)?;
+
+    // dot-atom: atext separated by single dots
+    let atext = |c: char| {
+        c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-".contains(c) || is_utf8_non_ascii(c)
+    };
+    if local.is_empty()
+        || local.len() > EMAIL_LOCAL_MAX_LENGTH
+        || local
+            .split('.')
+            .any(|atom| atom.is_empty() || !atom.chars().all(atext))
+    {
+        return Err(syntax());
+    }
+
+    // At least two labels, addresses at bare hostnames are not deliverable from the internet
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && label.len() <= EMAIL_LABEL_MAX_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || is_utf8_non_ascii(c))
+    };
+    if labels.len() < 2 || !labels.iter().all(valid_label) {
+        return Err(syntax());
+    }
+
+    Ok(())
+}
+
+// Non-ASCII characters allowed in internationalized addresses
+fn is_utf8_non_ascii(c: char) -> bool {
+    !c.is_ascii() && !c.is_control() && !c.is_whitespace()
+}
+
+/// A new password, normalized like RFC 8265 OpaqueString so it hashes the same everywhere
+pub fn password(value: &mut String) -> Result<(), Invalid> {
+    normalize(value);
+
+    existing_password(value)?;
+    if value.chars().any(char::is_control) {
+        return Err(Invalid::new(
+            "invalid_characters",
+            "must not contain control characters",
+        ));
+    }
+
+    Ok(())
+}
+
+/// A password checked against a stored hash, kept as typed because hashes from before
+/// passwords were normalized are of the raw value. `check_password` tries both.
+pub fn existing_password(value: &str) -> Result<(), Invalid> {
+    if value.is_empty() {
+        return Err(Invalid::new("required", "must not be empty"));
+    }
+    if value.chars().count() > PASSWORD_MAX_LENGTH {
+        return Err(Invalid::new(
+            "too_long",
+            format!("must be at most {} characters long", PASSWORD_MAX_LENGTH),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A BCP 47 language tag like `de` or `pt-BR`, brought into its conventional case
+pub fn locale(value: &mut String) -> Result<(), Invalid> {
+    normalize_identifier(value);
+
+    let syntax = || {
+        Invalid::new(
+            "invalid_locale",
+            "must be a language tag like 'en' or 'pt-BR'",
+        )
+    };
+    if value.is_empty() {
+        return Err(Invalid::new("required", "must not be empty"));
+    }
+    if value.len() > LOCALE_MAX_LENGTH {
+        return Err(Invalid::new(
+            "too_long",
+            format!("must be at most {} characters long", LOCALE_MAX_LENGTH),
+        ));
+    }
+
+    let mut subtags = value.split(['-', '_']);
+    let language = subtags.next().unwrap_or_default();
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(syntax());
+    }
+    let mut tag = language.to_ascii_lowercase();
+    for subtag in subtags {
+        if !(1..=8).contains(&subtag.len()) || !subtag.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(syntax());
+        }
+        tag.push('-');
+        // Regions are upper case and scripts title case, e.g. `zh-Hant-TW`
+        match subtag.len() {
+            2 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                tag.push_str(&subtag.to_ascii_uppercase())
+            }
+            4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                tag.push_str(&subtag[..1].to_ascii_uppercase());
+                tag.push_str(&subtag[1..].to_ascii_lowercase());
+            }
+            _ => tag.push_str(&subtag.to_ascii_lowercase()),
+        }
+    }
+    *value = tag;
+
+    Ok(())
+}
+
+/// Any other text that has to be present, e.g. a token copied from an email
+pub fn required(value: &mut String) -> Result<(), Invalid> {
+    normalize_identifier(value);
+
+    match value.is_empty() {
+        true => Err(Invalid::new("required", "must not be empty")),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The normalized value of accepted input
+    fn accepted(check: fn(&mut String) -> Result<(), Invalid>, input: &str) -> String {
+        let mut value = input.to_owned();
+        match check(&mut value) {
+            Ok(()) => value,
+            Err(invalid) => panic!("{:?} rejected with {}", input, invalid.code),
+        }
+    }
+
+    // The code of rejected input
+    fn rejected(check: fn(&mut String) -> Result<(), Invalid>, input: &str) -> &'static str {
+        match check(&mut input.to_owned()) {
+            Ok(()) => panic!("{:?} accepted", input),
+            Err(invalid) => invalid.code,
+        }
+    }
+
+    #[test]
+    fn username_charset_and_length() {
+        assert_eq!(accepted(username, "  alice.b_c-1 "), "alice.b_c-1");
+        assert_eq!(accepted(username, "Jürgen"), "Jürgen");
+        // Decomposed input is stored composed
+        assert_eq!(accepted(username, "Ju\u{308}rgen"), "Jürgen");
+
+        assert_eq!(rejected(username, "ab"), "too_short");
+        assert_eq!(rejected(username, &"a".repeat(33)), "too_long");
+        assert_eq!(accepted(username, &"a".repeat(32)), "a".repeat(32));
+        assert_eq!(rejected(username, "alice bob"), "invalid_characters");
+        assert_eq!(rejected(username, "alice@home"), "invalid_characters");
+        assert_eq!(rejected(username, ".alice"), "invalid_characters");
+    }
+
+    #[test]
+    fn login_name_accepts_legacy_names() {
+        assert_eq!(
+            accepted(login_name, " old name with spaces "),
+            "old name with spaces"
+        );
+        assert_eq!(accepted(login_name, "a"), "a");
+
+        assert_eq!(rejected(login_name, "   "), "required");
+        assert_eq!(rejected(login_name, &"a".repeat(255)), "too_long");
+    }
+
+    #[test]
+    fn email_syntax() {
+        assert_eq!(accepted(email, " alice@example.com "), "alice@example.com");
+        assert_eq!(
+            accepted(email, "a.b+tag@mail.example.co.uk"),
+            "a.b+tag@mail.example.co.uk"
+        );
+        assert_eq!(accepted(email, "jürgen@bücher.de"), "jürgen@bücher.de");
+
+        assert_eq!(rejected(email, ""), "required");
+        assert_eq!(rejected(email, "alice"), "invalid_email");
+        assert_eq!(rejected(email, "alice@localhost"), "invalid_email");
+        assert_eq!(rejected(email, "alice..b@example.com"), "invalid_email");
+        assert_eq!(rejected(email, ".alice@example.com"), "invalid_email");
+        assert_eq!(rejected(email, "alice@-example.com"), "invalid_email");
+        assert_eq!(rejected(email, "alice@example..com"), "invalid_email");
+        assert_eq!(rejected(email, "al ice@example.com"), "invalid_email");
+        assert_eq!(
+            rejected(email, &format!("{}@example.com", "a".repeat(65))),
+            "invalid_email"
+        );
+        assert_eq!(
+            rejected(email, &format!("alice@{}.com", "a".repeat(250))),
+            "too_long"
+        );
+    }
+
+    #[test]
+    fn new_password_is_normalized() {
+        // Surrounding spaces are part of a password
+        assert_eq!(accepted(password, " Cafe\u{301} "), " Café ");
+        assert_eq!(rejected(password, ""), "required");
+        assert_eq!(rejected(password, &"a".repeat(1025)), "too_long");
+        assert_eq!(rejected(password, "tab\there"), "invalid_characters");
+    }
+
+    #[test]
+    fn existing_password_is_kept_as_typed() {
+        assert_eq!(existing_password("Cafe\u{301}\u{7}"), Ok(()));
+        assert_eq!(existing_password("").unwrap_err().code, "required");
+        assert_eq!(
+            existing_password(&"a".repeat(1025)).unwrap_err().code,
+            "too_long"
+        );
+    }
+
+    #[test]
+    fn locale_is_canonicalized() {
+        assert_eq!(accepted(locale, "DE"), "de");
+        assert_eq!(accepted(locale, "pt_br"), "pt-BR");
+        assert_eq!(accepted(locale, "zh-hant-tw"), "zh-Hant-TW");
+        assert_eq!(accepted(locale, "es-419"), "es-419");
+
+        assert_eq!(rejected(locale, ""), "required");
+        assert_eq!(rejected(locale, "english"), "invalid_locale");
+        assert_eq!(rejected(locale, "de--AT"), "invalid_locale");
+        assert_eq!(rejected(locale, "de-toolongsubtag"), "invalid_locale");
+        assert_eq!(
+            rejected(locale, &format!("en-{}", "a-".repeat(20))),
+            "too_long"
+        );
+    }
+
+    #[test]
+    fn required_is_trimmed() {
+        assert_eq!(accepted(required, " token "), "token");
+        assert_eq!(rejected(required, " "), "required");
+    }
+}
@@ -0,0 +1,363 @@
+// Transactional emails, rendered from named templates
+//
+// A template is two files per locale, `<locale>/<name>.html` and `<locale>/<name>.txt`. The
+// text body sets the subject with `{% set subject = "..." %}`, the HTML body extends
+// `layout.html`, which shows it. Values are HTML escaped in `.html` files only. Files in
+// `--email-templates-dir` take precedence over the defaults compiled in from
+// `templates/email`, so a deployment can override single templates or add locales. A template
+// missing in a locale falls back to the default locale.
+use anyhow::Context;
+use minijinja::{Environment, ErrorKind, Value, context};
+use std::path::Path;
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+// Every template of a compiled-in locale, keyed by its path in the template directory
+macro_rules! builtin_locale {
+    ($locale:literal) => {
+        builtin_locale!(
+            $locale,
+            "verification",
+            "password_reset",
+            "email_change",
+            "email_change_notice",
+            "account_locked",
+            "registration_attempt",
+            "username_taken",
+            "welcome"
+        )
+    };
+    ($locale:literal, $($name:literal),*) => {
+        &[$(
+            (
+                concat!($locale, "/", $name, ".html"),
+                include_str!(concat!("../templates/email/", $locale, "/", $name, ".html")),
+            ),
+            (
+                concat!($locale, "/", $name, ".txt"),
+                include_str!(concat!("../templates/email/", $locale, "/", $name, ".txt")),
+            ),
+        )*]
+    };
+}
+
+const BUILTIN_LOCALES: [&str; 2] = ["en", "de"];
+
+const BUILTIN: &[&[(&str, &str)]] = &[
+    &[(
+        "layout.html",
+        include_str!("../templates/email/layout.html"),
+    )],
+    builtin_locale!("en"),
+    builtin_locale!("de"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Template {
+    Verification,
+    PasswordReset,
+    EmailChange,
+    EmailChangeNotice,
+    AccountLocked,
+    RegistrationAttempt,
+    UsernameTaken,
+    Welcome,
+}
+
+impl Template {
+    pub const ALL: [Self; 8] = [
+        Self::Verification,
+        Self::PasswordReset,
+        Self::EmailChange,
+        Self::EmailChangeNotice,
+        Self::AccountLocked,
+        Self::RegistrationAttempt,
+        Self::UsernameTaken,
+        Self::Welcome,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Verification => "verification",
+            Self::PasswordReset => "password_reset",
+            Self::EmailChange => "email_change",
+            Self::EmailChangeNotice => "email_change_notice",
+            Self::AccountLocked => "account_locked",
+            Self::RegistrationAttempt => "registration_attempt",
+            Self::UsernameTaken => "username_taken",
+            Self::Welcome => "welcome",
+        }
+    }
+}
+
+/// A rendered email, ready to be queued
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rendered {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+pub struct Templates {
+    env: Environment<'static>,
+    // Names of the locale directories, matched case insensitively
+    locales: Vec<String>,
+}
+
+impl Templates {
+    /// Load the templates, every locale directory in `dir` becomes a supported locale.
+    /// Every template is compiled once, so a broken override fails at startup.
+    pub fn new(dir: Option<&Path>, app_name: &str) -> anyhow::Result<Self> {
+        let mut locales: Vec<String> = BUILTIN_LOCALES.iter().map(|l| l.to_string()).collect();
+        if let Some(dir) = dir {
+            let entries = std::fs::read_dir(dir)
+                .with_context(|| format!("could not read {}", dir.display()))?;
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_dir()
+                    && let Some(name) = entry.file_name().to_str()
+                    && !locales.iter().any(|l| l.eq_ignore_ascii_case(name))
+                {
+                    locales.push(name.to_owned());
+                }
+            }
+        }
+
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.add_global("app_name", app_name.to_owned());
+        let dir = dir.map(Path::to_path_buf);
+        env.set_loader(move |name| load(dir.as_deref(), name));
+
+        let templates = Self { env, locales };
+        for locale in &templates.locales {
+            for template in Template::ALL {
+                for extension in ["html", "txt"] {
+                    templates
+                        .get(locale, template, extension)
+                        .with_context(|| format!("invalid email template {}", template.as_str()))?;
+                }
+            }
+        }
+
+        Ok(templates)
+    }
+
+    /// The supported locale closest to a language tag, `de-AT` falls back to `de`
+    pub fn negotiate(&self, tag: &str) -> Option<&str> {
+        let mut tag = tag.trim();
+        loop {
+            if let Some(locale) = self.locales.iter().find(|l| l.eq_ignore_ascii_case(tag)) {
+                return Some(locale);
+            }
+            tag = tag.rsplit_once('-')?.0;
+        }
+    }
+
+    /// Render both bodies of a template in the locale closest to `locale`
+    pub fn render(
+        &self,
+        template: Template,
+        locale: Option<&str>,
+        values: Value,
+    ) -> anyhow::Result<Rendered> {
+        let locale = locale
+            .and_then(|locale| self.negotiate(locale))
+            .unwrap_or(DEFAULT_LOCALE);
+
+        let text = self
+            .get(locale, template, "txt")?
+            .render_captured(context! { locale, ..values.clone() })?;
+        let subject = match text.state().lookup("subject") {
+            // A single line, interpolated values must not be able to add headers
+            Some(subject) => subject
+                .to_string()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            None => anyhow::bail!("email template {} sets no subject", template.as_str()),
+        };
+        let text = text.into_output();
+
+        let html = self
+            .get(locale, template, "html")?
+            .render(context! { locale, subject, ..values })?;
+
+        Ok(Rendered {
+            subject,
+            html,
+            text,
+        })
+    }
+
+    fn get(
+        &self,
+        locale: &str,
+        template: Template,
+        extension: &str,
+    ) -> Result<minijinja::Template<'_, '_>, minijinja::Error> {
+        let name = format!("{}/{}.{}", locale, template.as_str(), extension);
+        match self.env.get_template(&name) {
+            Err(e) if e.kind() == ErrorKind::TemplateNotFound && locale != DEFAULT_LOCALE => {
+                self.get(DEFAULT_LOCALE, template, extension)
+            }
+            result => result,
+        }
+    }
+}
+
+// A file in the template directory, or the compiled-in default
+fn load(dir: Option<&Path>, name: &str) -> Result<Option<String>, minijinja::Error> {
+    if let Some(dir) = dir {
+        match std::fs::read_to_string(dir.join(name)) {
+            Ok(source) => return Ok(Some(source)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(minijinja::Error::new(
+                    ErrorKind::InvalidOperation,
+                    format!("could not read email template {}", name),
+                )
+                .with_source(e));
+            }
+        }
+    }
+
+    Ok(BUILTIN
+        .iter()
+        .flat_map(|templates| templates.iter())
+        .find(|(path, _)| *path == name)
+        .map(|(_, source)| source.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(username: &str) -> Value {
+        context! {
+            username,
+            new_email => "new@example.com",
+            expires_in_minutes => 60,
+            locked_for_minutes => 15,
+            verification_link => "https://api.example.com/users/verify/id.secret",
+            reset_link => "https://app.example.com/reset-password/id.secret",
+            confirm_link => "https://app.example.com/confirm-email/id.secret",
+            cancel_link => "https://app.example.com/cancel-email-change/id.secret",
+            login_link => "https://app.example.com/login",
+            register_link => "https://app.example.com/register",
+        }
+    }
+
+    fn snapshot(rendered: &Rendered) -> String {
+        format!(
+            "Subject: {}\n\n--- html\n{}\n--- txt\n{}",
+            rendered.subject, rendered.html, rendered.text
+        )
+    }
+
+    #[test]
+    fn every_template_in_every_locale() {
+        let templates = Templates::new(None, "Example").unwrap();
+        for locale in BUILTIN_LOCALES {
+            for template in Template::ALL {
+                let rendered = templates
+                    .render(template, Some(locale), values("alice"))
+                    .unwrap();
+                insta::assert_snapshot!(
+                    format!("{}_{}", locale, template.as_str()),
+                    snapshot(&rendered)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_only() {
+        let templates = Templates::new(None, "Example").unwrap();
+        let rendered = templates
+            .render(Template::Welcome, None, values("<script>alert(1)</script>"))
+            .unwrap();
+
+        assert!(!rendered.html.contains("<script>"));
+        assert!(
+            rendered
+                .html
+                .contains("&lt;script&gt;alert(1)&lt;&#x2f;script&gt;")
+        );
+        assert!(rendered.text.contains("<script>alert(1)</script>"));
+    }
+
+    #[test]
+    fn accept_language_is_negotiated() {
+        let templates = Templates::new(None, "Example").unwrap();
+        assert_eq!(templates.negotiate("de"), Some("de"));
+        assert_eq!(templates.negotiate("DE-at"), Some("de"));
+        assert_eq!(templates.negotiate("en-US"), Some("en"));
+        assert_eq!(templates.negotiate("fr-FR"), None);
+
+        let subject = |locale| {
+            templates
+                .render(Template::Welcome, locale, values("alice"))
+                .unwrap()
+                .subject
+        };
+        assert_eq!(subject(Some("de-CH")), "Willkommen bei Example");
+        assert_eq!(subject(Some("fr")), "Welcome to Example");
+        assert_eq!(subject(None), "Welcome to Example");
+    }
+
+    #[test]
+    fn directory_overrides_templates_and_adds_locales() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("en")).unwrap();
+        std::fs::write(
+            dir.path().join("en/welcome.txt"),
+            "{% set subject = \"Hello \" ~ username %}\nCustom welcome",
+        )
+        .unwrap();
+        std::fs::create_dir_all(dir.path().join("fr")).unwrap();
+        std::fs::write(
+            dir.path().join("fr/welcome.txt"),
+            "{% set subject = \"Bienvenue\" %}\nBonjour {{ username }}",
+        )
+        .unwrap();
+
+        let templates = Templates::new(Some(dir.path()), "Example").unwrap();
+
+        let welcome = templates
+            .render(Template::Welcome, Some("en"), values("alice"))
+            .unwrap();
+        assert_eq!(welcome.subject, "Hello alice");
+        assert_eq!(welcome.text, "Custom welcome");
+        // The HTML body is not overridden, the compiled-in one shows the new subject
+        assert!(welcome.html.contains("Hello alice"));
+
+        let french = templates
+            .render(Template::Welcome, Some("fr-CA"), values("alice"))
+            .unwrap();
+        assert_eq!(french.subject, "Bienvenue");
+        assert_eq!(french.text, "Bonjour alice");
+
+        // Templates missing in the added locale fall back to the default one
+        let reset = templates
+            .render(Template::PasswordReset, Some("fr"), values("alice"))
+            .unwrap();
+        assert_eq!(
+            reset.subject,
+            templates
+                .render(Template::PasswordReset, Some("en"), values("alice"))
+                .unwrap()
+                .subject
+        );
+    }
+
+    #[test]
+    fn broken_override_fails_at_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("en")).unwrap();
+        std::fs::write(dir.path().join("en/welcome.html"), "{% if %}").unwrap();
+
+        assert!(Templates::new(Some(dir.path()), "Example").is_err());
+    }
+}
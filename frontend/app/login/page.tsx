@@ -36,6 +36,7 @@ export default function LoginPage() {
     } catch (err: any) {
       setIsLoading(false);
       if (err.response?.status === 302) router.push('/');
+      else if (err.response?.status === 403) setError('Please verify your email address before signing in.');
       else setError('Invalid username or password.');
     }
   };
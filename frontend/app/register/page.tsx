@@ -1,12 +1,10 @@
 "use client";
 import { useState } from 'react';
-import { useAuth } from '../context/AuthContext';
 import api from '../lib/api';
 import Link from 'next/link';
 import { useRouter } from 'next/navigation';
 
 export default function RegisterPage() {
-    const { login } = useAuth();
     const router = useRouter();
     const [formData, setFormData] = useState({
         username: '',
@@ -14,33 +12,36 @@ export default function RegisterPage() {
         password: ''
     });
     const [error, setError] = useState('');
+    // Rules the server rejected the password or other fields for, one message each
+    const [violations, setViolations] = useState<string[]>([]);
     const [isLoading, setIsLoading] = useState(false);
 
     const handleSubmit = async (e: React.FormEvent) => {
         e.preventDefault();
         setIsLoading(true);
         setError('');
-
-        if (formData.password.length < 8) {
-            setError('Password must be at least 8 characters');
-            setIsLoading(false);
-            return;
-        }
+        setViolations([]);
 
         try {
             await api.post('/users/create-user', formData);
 
-            // 3. Auto-login after successful registration
-            // We use the same credentials to get a token immediately
-            const { data } = await api.post('/token/get', {
-                username: formData.username,
-                password: formData.password
-            });
-
-            login(data.token);
+            // The account has to be verified through the emailed link before the first login
+            router.push('/login?registered=true');
         } catch (err: any) {
-            // Handle "User already exists" or generic errors
-            if (err.response?.status === 409 || err.response?.status === 401) {
+            // Handle password policy, "User already exists" or generic errors
+            if (err.response?.status === 422 && err.response.data?.violations) {
+                setError('Password does not meet the requirements. It:');
+                setViolations(
+                    err.response.data.violations.map((v: { message: string }) => v.message)
+                );
+            } else if (err.response?.status === 422 && err.response.data?.errors) {
+                setError('Please correct the following:');
+                setViolations(
+                    err.response.data.errors.map(
+                        (f: { field: string; message: string }) => `${f.field} ${f.message}`
+                    )
+                );
+            } else if (err.response?.status === 409 || err.response?.status === 401) {
                 setError('User with this email or username already exists.');
             } else {
                 setError('Registration failed. Please try again.');
@@ -65,6 +66,13 @@ export default function RegisterPage() {
                 {error && (
                     <div className="rounded-md bg-red-50 p-4 text-sm text-red-700">
                         {error}
+                        {violations.length > 0 && (
+                            <ul className="mt-2 list-disc pl-5">
+                                {violations.map((message) => (
+                                    <li key={message}>{message}</li>
+                                ))}
+                            </ul>
+                        )}
                     </div>
                 )}
 